@@ -1,7 +1,9 @@
 use futures::Future;
 use rtrpc_client::*;
 use rtrpc_common::*;
-use std::env::args;
+use std::env::{args, var};
+use std::io::Error;
+use std::path::Path;
 use tokio_core::reactor::Core;
 
 fn main() {
@@ -24,8 +26,39 @@ fn main() {
         1=>panic!("IP address not provided"),
         _=>&argvec[1]
     };
-    let addr = arg1.parse().unwrap();
-    let client = Client::new(handle, addr);
+    let addr: Address = arg1.parse().unwrap();
+    let mut client = Client::new(handle, addr)
+        .with_websocket(var("RTRPC_WEBSOCKET").is_ok_and(|value| value == "1"));
+    if let Some(name) = argvec.get(2) {
+        client = client.with_encoding(Encoding::from_name(name).expect("Unknown encoding"));
+    }
+    if let Some(name) = argvec.get(3) {
+        client = client.with_compression(Compression::from_name(name).expect("Unknown compression"));
+    }
+    if let Some(name) = argvec.get(4) {
+        client = client.with_checksum(Checksum::from_name(name).expect("Unknown checksum"));
+    }
+    if let Ok(ca) = var("RTRPC_TLS_CA") {
+        let cert = var("RTRPC_TLS_CLIENT_CERT").ok();
+        let key = var("RTRPC_TLS_CLIENT_KEY").ok();
+        let identity = match (&cert, &key) {
+            (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+            _ => None,
+        };
+        let connector = tls_connector(Path::new(&ca), identity).expect("Can not load the TLS certificate");
+        let domain = var("RTRPC_TLS_DOMAIN").unwrap_or_else(|_| String::from("localhost"));
+        client = client.with_tls(connector, &domain);
+    }
+    if let Ok(token) = var("RTRPC_TOKEN") {
+        client = client.with_token(&token);
+    }
+    if let (Ok(principal), Ok(secret)) = (var("RTRPC_PRINCIPAL"), var("RTRPC_HMAC_SECRET")) {
+        client = client.with_hmac(&principal, secret.as_bytes());
+    }
+    match core.run(client.server_info()) {
+        Ok(info) => println!("server : {:?}", info),
+        Err(err) => println!("error : {:?}", err),
+    }
     let mut request=|graph,start,end|{
         println!("request : {:?}", (graph, start, end));
         let rpc = client
@@ -41,4 +74,54 @@ fn main() {
     request(&graph,"a","e");
     request(&graph,"f","h");
     request(&graph,"a","i");
+    let mut run = |name, rpc: Box<dyn Future<Item = String, Error = Error>>| {
+        println!("request : {}", name);
+        match core.run(rpc) {
+            Ok(result) => println!("result : {}", result),
+            Err(err) => println!("error : {:?}", err),
+        }
+    };
+    run(
+        "shortest path within 3 hops from f to h",
+        Box::new(client.shortest_path_within(&graph, "f", "h", 3).map(|r| format!("{:?}", r))),
+    );
+    run(
+        "strongly connected components",
+        Box::new(client.strongly_connected_components(&graph).map(|r| format!("{:?}", r))),
+    );
+    run(
+        "topological sort",
+        Box::new(client.topological_sort(&graph).map(|r| format!("{:?}", r))),
+    );
+    run(
+        "reachable from c",
+        Box::new(client.reachable(&graph, "c").map(|r| format!("{:?}", r))),
+    );
+    run(
+        "co-reachable to c",
+        Box::new(client.co_reachable(&graph, "c").map(|r| format!("{:?}", r))),
+    );
+    run(
+        "maximum flow from a to e",
+        Box::new(client.maximum_flow(&graph, "a", "e").map(|r| format!("{:?}", r))),
+    );
+    run(
+        "minimum spanning forest",
+        Box::new(client.minimum_spanning_forest(&graph).map(|r| format!("{:?}", r))),
+    );
+    let network = FlowNetwork::from_edges(&[
+        ("s", "a", 4.0, 1.0),
+        ("s", "b", 2.0, 4.0),
+        ("a", "b", 2.0, -2.0),
+        ("a", "t", 2.0, 6.0),
+        ("b", "t", 3.0, 1.0),
+    ]);
+    run(
+        "minimum cost flow from s to t",
+        Box::new(
+            client
+                .minimum_cost_flow(&network, "s", "t", None)
+                .map(|r| format!("{:?}", r)),
+        ),
+    );
 }
@@ -1,21 +1,129 @@
+use futures::future::{self, Either};
 use futures::{Future, Sink, Stream};
 use rtrpc_common::*;
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::codec::Framed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixStream;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
+use tokio_rustls::webpki::DNSNameRef;
 
 /// A client used to invoke rpc request
 pub struct Client {
     handle: Handle,
-    addr: SocketAddr,
+    addr: Address,
+    features: Features,
+    options: DecodeOptions,
+    compression_threshold: usize,
+    websocket: bool,
+    tls: Option<(TlsConnector, String)>,
+    identity: Option<Identity>,
+}
+
+/// The secret the client authenticates with
+#[derive(Clone)]
+enum Identity {
+    Token(String),
+    Hmac { principal: String, secret: Vec<u8> },
+}
+
+impl Identity {
+    /// The credentials sent in the hello, the HMAC ones are signed at the current time
+    fn credentials(&self) -> Credentials {
+        match self {
+            Identity::Token(token) => Credentials::Token(token.clone()),
+            Identity::Hmac { principal, secret } => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0);
+                Credentials::hmac(principal, secret, timestamp)
+            }
+        }
+    }
 }
 
 impl Client {
-    /// Create a client with the handle and address of the server
-    pub fn new(handle: Handle, addr: SocketAddr) -> Self {
-        Client { handle, addr }
+    /// Create a client with the handle and address of the server,
+    /// which is a TCP address or the path of a Unix domain socket
+    pub fn new(handle: Handle, addr: impl Into<Address>) -> Self {
+        Client {
+            handle,
+            addr: addr.into(),
+            features: Features::supported(),
+            options: DecodeOptions::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            websocket: false,
+            tls: None,
+            identity: None,
+        }
+    }
+    /// Set the limits used to reject oversized frames and payloads
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+    /// Accept the invalid UTF-8 strings by replacing the invalid sequences,
+    /// instead of rejecting the respond
+    pub fn with_lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.options.lossy_utf8 = lossy_utf8;
+        self
+    }
+    /// Prefer the encoding in the handshake, the native encoding is
+    /// still used when the server does not support it
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        let encodings = &mut self.features.encodings;
+        encodings.retain(|name| name != encoding.name());
+        encodings.insert(0, String::from(encoding.name()));
+        self
+    }
+    /// Prefer the compression in the handshake, the frames are not
+    /// compressed when the server does not support it
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        let compressions = &mut self.features.compressions;
+        compressions.retain(|name| name != compression.name());
+        compressions.insert(0, String::from(compression.name()));
+        self
+    }
+    /// Prefer the checksum in the handshake, the frames are not
+    /// checked when the server does not support it
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        let checksums = &mut self.features.checksums;
+        checksums.retain(|name| name != checksum.name());
+        checksums.insert(0, String::from(checksum.name()));
+        self
+    }
+    /// Set the size from which the requests are compressed
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+    /// Carry the packets over WebSocket binary messages, the address
+    /// must be the WebSocket port of the server
+    pub fn with_websocket(mut self, websocket: bool) -> Self {
+        self.websocket = websocket;
+        self
+    }
+    /// Connect with TLS, the certificate of the server must be valid for the domain
+    pub fn with_tls(mut self, connector: TlsConnector, domain: &str) -> Self {
+        self.tls = Some((connector, String::from(domain)));
+        self
+    }
+    /// Authenticate with the shared secret token of the principal
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.identity = Some(Identity::Token(String::from(token)));
+        self
+    }
+    /// Authenticate as the principal by signing the time of each connection with the secret,
+    /// the clock must be within a few minutes of the server
+    pub fn with_hmac(mut self, principal: &str, secret: &[u8]) -> Self {
+        self.identity = Some(Identity::Hmac {
+            principal: String::from(principal),
+            secret: secret.to_vec(),
+        });
+        self
     }
     /// Find the shortest path from the start point to the end point
     pub fn shortest_path(
@@ -24,35 +132,358 @@ impl Client {
         start: &str,
         end: &str,
     ) -> impl Future<Item = Result<Option<Vec<String>>, NegativeCycle>, Error = Error> {
-        let graph = graph.clone();
-        let start = String::from(start);
-        let end = String::from(end);
+        self.shortest_path_request(graph, start, end, None)
+    }
+    /// Find the shortest path from the start point to the end point
+    /// which use at most `max_hops` edges, this never fails with `NegativeCycle`
+    pub fn shortest_path_within(
+        &self,
+        graph: &Graph,
+        start: &str,
+        end: &str,
+        max_hops: u32,
+    ) -> impl Future<Item = Result<Option<Vec<String>>, NegativeCycle>, Error = Error> {
+        self.shortest_path_request(graph, start, end, Some(max_hops))
+    }
+    fn shortest_path_request(
+        &self,
+        graph: &Graph,
+        start: &str,
+        end: &str,
+        max_hops: Option<u32>,
+    ) -> impl Future<Item = Result<Option<Vec<String>>, NegativeCycle>, Error = Error> {
+        let request = Request::ShortestPath {
+            graph: graph.clone(),
+            start: String::from(start),
+            end: String::from(end),
+            max_hops,
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::ShortestPath(result) => Ok(result),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Find the strongly connected components of the graph
+    pub fn strongly_connected_components(
+        &self,
+        graph: &Graph,
+    ) -> impl Future<Item = Vec<Vec<String>>, Error = Error> {
+        let request = Request::StronglyConnectedComponents {
+            graph: graph.clone(),
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::StronglyConnectedComponents(components) => Ok(components),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Sort the nodes of the graph in topological order,
+    /// or find a cycle when the graph is not acyclic
+    pub fn topological_sort(
+        &self,
+        graph: &Graph,
+    ) -> impl Future<Item = Result<Vec<String>, Cycle>, Error = Error> {
+        let request = Request::TopologicalSort {
+            graph: graph.clone(),
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::TopologicalSort(result) => Ok(result),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Find the nodes reachable from the node
+    pub fn reachable(
+        &self,
+        graph: &Graph,
+        node: &str,
+    ) -> impl Future<Item = Option<Vec<String>>, Error = Error> {
+        let request = Request::Reachable {
+            graph: graph.clone(),
+            node: String::from(node),
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::Reachable(nodes) => Ok(nodes),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Find the nodes which can reach the node
+    pub fn co_reachable(
+        &self,
+        graph: &Graph,
+        node: &str,
+    ) -> impl Future<Item = Option<Vec<String>>, Error = Error> {
+        let request = Request::CoReachable {
+            graph: graph.clone(),
+            node: String::from(node),
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::CoReachable(nodes) => Ok(nodes),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Find the maximum flow and the minimum cut from source to sink,
+    /// using the edge weights as capacities
+    pub fn maximum_flow(
+        &self,
+        graph: &Graph,
+        source: &str,
+        sink: &str,
+    ) -> impl Future<Item = Result<Option<MaxFlow>, InvalidWeight>, Error = Error> {
+        let request = Request::MaximumFlow {
+            graph: graph.clone(),
+            source: String::from(source),
+            sink: String::from(sink),
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::MaximumFlow(result) => Ok(result),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Find the minimum spanning forest, treating the edges as undirected
+    pub fn minimum_spanning_forest(
+        &self,
+        graph: &Graph,
+    ) -> impl Future<Item = Result<SpanningForest, InvalidWeight>, Error = Error> {
+        let request = Request::MinimumSpanningForest {
+            graph: graph.clone(),
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::MinimumSpanningForest(result) => Ok(result),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Find the flow with minimum cost from source to sink,
+    /// which is the maximum flow when `amount` is not provided
+    pub fn minimum_cost_flow(
+        &self,
+        network: &FlowNetwork,
+        source: &str,
+        sink: &str,
+        amount: Option<f64>,
+    ) -> impl Future<Item = Result<Option<MinCostFlow>, MinCostFlowError>, Error = Error> {
+        let request = Request::MinimumCostFlow {
+            network: network.clone(),
+            source: String::from(source),
+            sink: String::from(sink),
+            amount,
+        };
+        self.call(&request).and_then(|respond| match respond {
+            Respond::MinimumCostFlow(result) => Ok(result),
+            _ => Err(unexpected_respond()),
+        })
+    }
+    /// Check the liveness and readiness of the server
+    pub fn health(&self) -> impl Future<Item = Health, Error = Error> {
+        self.call(&Request::Health {})
+            .and_then(|respond| match respond {
+                Respond::Health(health) => Ok(health),
+                _ => Err(unexpected_respond()),
+            })
+    }
+    /// Describe the server, to check the compatibility before sending work
+    pub fn server_info(&self) -> impl Future<Item = ServerInfo, Error = Error> {
+        self.call(&Request::ServerInfo {})
+            .and_then(|respond| match respond {
+                Respond::ServerInfo(info) => Ok(info),
+                _ => Err(unexpected_respond()),
+            })
+    }
+    /// Connect to the server and return the features agreed in the handshake,
+    /// which can be used to check the compatibility before sending requests
+    pub fn handshake(&self) -> impl Future<Item = Agreement, Error = Error> {
+        self.connect().map(|(_, agreement)| agreement)
+    }
+    /// Send the request to the server and wait for the respond,
+    /// `Respond::Error` from the server is returned as an error
+    pub fn call(&self, request: &Request) -> impl Future<Item = Respond, Error = Error> {
+        let method = request.method();
+        let request = request.clone();
+        let options = self.options.clone();
         // currently we create one connection for each request.
-        TcpStream::connect(&self.addr, &self.handle).and_then(move |sock| {
-            let (sink, stream) = Framed::new(sock, PacketCodec::new()).split();
-            let send = sink
-                .send(Packet {
-                    id: 0xDEADBEEF,
-                    payload: encode_request(&graph, &start, &end),
-                })
-                .and_then(|sink| sink.flush());
-            let recieve = stream
-                .into_future()
-                .map_err(|(err, _)| err)
-                .and_then(|(packet, _)| {
-                    if let Some(Packet { id, payload }) = packet {
-                        if id != 0xDEADBEEF {
-                            return Err(Error::new(ErrorKind::InvalidData, "Invalid packet id."));
-                        }
-                        decode_respond(payload).ok_or(Error::new(
-                            ErrorKind::InvalidData,
-                            "Can not parse respond packet.",
-                        ))
-                    } else {
-                        Err(Error::new(ErrorKind::InvalidData, "No packet returned."))
+        self.connect()
+            .and_then(move |(framed, agreement)| {
+                if !agreement.methods.iter().any(|m| m == method) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("The server does not support method {}.", method),
+                    ));
+                }
+                let payload = encode_request(&request, framed.codec().encoding())?;
+                Ok((framed, payload))
+            })
+            .and_then(move |(framed, payload)| {
+                let encoding = framed.codec().encoding();
+                let options = DecodeOptions {
+                    encoding,
+                    ..options
+                };
+                let (sink, stream) = framed.split();
+                let send = sink
+                    .send(Packet {
+                        id: 0xDEADBEEF,
+                        payload,
+                    })
+                    .and_then(|sink| sink.flush());
+                let recieve =
+                    stream
+                        .into_future()
+                        .map_err(|(err, _)| err)
+                        .and_then(move |(packet, _)| {
+                            if let Some(Packet { id, payload }) = packet {
+                                // the going away notice and the corrupted request respond of the
+                                // server have the magic id
+                                if id != 0xDEADBEEF && id != MAGIC {
+                                    return Err(Error::new(
+                                        ErrorKind::InvalidData,
+                                        "Invalid packet id.",
+                                    ));
+                                }
+                                match decode_respond(payload, &options) {
+                                    Ok(Respond::Error(ServerError::GoingAway)) => Err(Error::new(
+                                        ErrorKind::ConnectionAborted,
+                                        ServerError::GoingAway,
+                                    )),
+                                    Ok(Respond::Error(err)) => {
+                                        Err(Error::new(ErrorKind::InvalidData, err))
+                                    }
+                                    Ok(respond) => Ok(respond),
+                                    Err(err) => Err(Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!("Can not parse respond packet: {}", err),
+                                    )),
+                                }
+                            } else {
+                                Err(Error::new(ErrorKind::InvalidData, "No packet returned."))
+                            }
+                        });
+                send.join(recieve).and_then(|(_, result)| Ok(result))
+            })
+    }
+    /// Open a connection to the server and finish the handshake
+    fn connect(
+        &self,
+    ) -> impl Future<Item = (Framed<Box<dyn Transport>, PacketCodec>, Agreement), Error = Error>
+    {
+        let hello = Hello {
+            credentials: self.identity.as_ref().map(Identity::credentials),
+            ..Hello::new(self.features.clone())
+        };
+        let options = self.options.clone();
+        let compression_threshold = self.compression_threshold;
+        let websocket = self.websocket;
+        let (sock, host) = match &self.addr {
+            Address::Tcp(addr) => {
+                let sock = TcpStream::connect(addr, &self.handle);
+                let sock = sock.map(|sock| Box::new(sock) as Box<dyn Transport>);
+                (Either::A(sock), addr.to_string())
+            }
+            Address::Unix(path) => {
+                let sock = UnixStream::connect(path);
+                let sock = sock.map(|sock| Box::new(sock) as Box<dyn Transport>);
+                (Either::B(sock), String::from("localhost"))
+            }
+        };
+        let (sock, url) = match self.tls.clone() {
+            Some((connector, domain)) => {
+                let url = format!("wss://{}/", domain);
+                let sock =
+                    sock.and_then(move |sock| match DNSNameRef::try_from_ascii_str(&domain) {
+                        Ok(name) => Either::A(
+                            connector
+                                .connect(name, sock)
+                                .map(|stream| Box::new(stream) as Box<dyn Transport>),
+                        ),
+                        Err(_) => Either::B(future::err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Invalid TLS domain {}.", domain),
+                        ))),
+                    });
+                (Either::A(sock), url)
+            }
+            None => (Either::B(sock), format!("ws://{}/", host)),
+        };
+        let sock = sock.and_then(move |sock| {
+            if websocket {
+                let max_message_size = websocket_message_size(&options.limits);
+                Either::A(
+                    connect_websocket(sock, url, max_message_size)
+                        .map(|stream| Box::new(stream) as Box<dyn Transport>),
+                )
+            } else {
+                Either::B(future::ok(Box::new(sock) as Box<dyn Transport>))
+            }
+        });
+        let options = self.options.clone();
+        sock.and_then(move |sock| {
+            let framed = Framed::new(sock, PacketCodec::with_limits(&options.limits));
+            handshake(framed, hello, &options).and_then(move |(mut framed, agreement)| {
+                let compression = Compression::from_name(&agreement.compression);
+                let checksum = Checksum::from_name(&agreement.checksum);
+                match (compression, checksum) {
+                    (Some(compression), Some(checksum)) => {
+                        let codec = framed.codec_mut();
+                        codec.set_compression(compression, compression_threshold);
+                        codec.set_checksum(checksum);
+                        Ok((framed, agreement))
                     }
-                });
-            send.join(recieve).and_then(|(_, result)| Ok(result))
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Unknown compression {} or checksum {} agreed.",
+                            agreement.compression, agreement.checksum
+                        ),
+                    )),
+                }
+            })
         })
     }
 }
+
+/// Send the hello packet on a new connection and wait for the welcome,
+/// returns the connection and the agreed features when it is accepted.
+fn handshake<T>(
+    framed: Framed<T, PacketCodec>,
+    hello: Hello,
+    options: &DecodeOptions,
+) -> impl Future<Item = (Framed<T, PacketCodec>, Agreement), Error = Error>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let options = options.clone();
+    framed
+        .send(Packet {
+            id: MAGIC,
+            payload: encode_hello(&hello),
+        })
+        .and_then(|framed| framed.into_future().map_err(|(err, _)| err))
+        .and_then(move |(packet, mut framed)| match packet {
+            Some(Packet { id: MAGIC, payload }) => match decode_welcome(payload, &options) {
+                Ok(Welcome::Accept(agreement)) => match Encoding::from_name(&agreement.encoding) {
+                    Some(encoding) => {
+                        framed.codec_mut().set_encoding(encoding);
+                        Ok((framed, agreement))
+                    }
+                    None => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown encoding {} agreed.", agreement.encoding),
+                    )),
+                },
+                Ok(Welcome::Reject(reason)) => Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("Handshake rejected: {}", reason),
+                )),
+                Err(err) => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Can not parse welcome packet: {}", err),
+                )),
+            },
+            Some(_) => Err(Error::new(ErrorKind::InvalidData, "Invalid packet id.")),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                "No welcome returned, the server may not speak this protocol.",
+            )),
+        })
+}
+
+fn unexpected_respond() -> Error {
+    Error::new(ErrorKind::InvalidData, "Unexpected respond.")
+}
@@ -0,0 +1,73 @@
+use crate::convert::DecodeError;
+use crate::handshake::Compression;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io::{Error, ErrorKind};
+
+/// The zstd level used for the frames, which favours speed like LZ4
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compress the payload, the result starts with the original length as u32.
+/// Returns `None` when the compressed payload is not smaller.
+pub(crate) fn compress(compression: Compression, payload: &[u8]) -> Option<Bytes> {
+    let compressed = match compression {
+        Compression::None => return None,
+        Compression::Lz4 => lz4_flex::block::compress(payload),
+        Compression::Zstd => zstd::bulk::compress(payload, ZSTD_LEVEL).ok()?,
+    };
+    if compressed.len() + 4 >= payload.len() {
+        return None;
+    }
+    let mut buf = BytesMut::with_capacity(4 + compressed.len());
+    buf.put_u32_be(payload.len() as u32);
+    buf.put_slice(&compressed);
+    Some(buf.freeze())
+}
+
+/// Restore the payload made by `compress`, which must not be longer than `limit`
+pub(crate) fn decompress(
+    compression: Compression,
+    data: &[u8],
+    limit: usize,
+) -> Result<Bytes, Error> {
+    if data.len() < 4 {
+        return Err(DecodeError::Malformed.into());
+    }
+    let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if length > limit {
+        return Err(DecodeError::TooLarge {
+            what: "decompressed length",
+            length,
+            limit,
+        }
+        .into());
+    }
+    let payload = match compression {
+        Compression::None => return Err(DecodeError::Malformed.into()),
+        Compression::Lz4 => lz4_flex::block::decompress(&data[4..], length).map_err(invalid)?,
+        Compression::Zstd => zstd::bulk::decompress(&data[4..], length).map_err(invalid)?,
+    };
+    if payload.len() != length {
+        return Err(DecodeError::Malformed.into());
+    }
+    Ok(Bytes::from(payload))
+}
+
+fn invalid<E>(_err: E) -> Error {
+    Error::new(ErrorKind::InvalidData, "Can not decompress the frame.")
+}
+
+#[test]
+fn compression_test() {
+    let payload = b"node-name,".repeat(100);
+    for &compression in &[Compression::Lz4, Compression::Zstd] {
+        let compressed = compress(compression, &payload).unwrap();
+        assert!(compressed.len() < payload.len() / 4);
+        assert_eq!(
+            decompress(compression, &compressed, payload.len()).unwrap(),
+            Bytes::from(&payload[..])
+        );
+        assert!(decompress(compression, &compressed, payload.len() - 1).is_err());
+        assert!(decompress(compression, &compressed[..compressed.len() - 1], 4096).is_err());
+        assert_eq!(compress(compression, b"ab"), None);
+    }
+}
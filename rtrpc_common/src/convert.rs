@@ -1,82 +1,368 @@
+use crate::format::{deserialize, serialize};
 use crate::graph::*;
+use crate::handshake::*;
+use crate::limits::*;
+use crate::request::*;
 use bytes::*;
+use std::error::Error;
+use std::{fmt, io, str};
+
+/// An error found when decoding a payload
+#[derive(Debug, PartialEq, Clone)]
+pub enum DecodeError {
+    /// The payload ends in the middle of a value, or has bytes left after it
+    Malformed,
+    /// A tag byte which does not name any variant
+    UnknownTag(u8),
+    /// A string is not valid UTF-8 while the lossy mode is not enabled
+    InvalidUtf8,
+    /// The payload is rejected by the serde format, with the message of the format
+    Format(String),
+    /// A length or count exceeds the configured limit
+    TooLarge {
+        what: &'static str,
+        length: usize,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Malformed => write!(f, "Malformed payload."),
+            DecodeError::UnknownTag(tag) => write!(f, "Unknown tag {}.", tag),
+            DecodeError::InvalidUtf8 => write!(f, "Invalid UTF-8 string."),
+            DecodeError::Format(message) => write!(f, "Invalid payload: {}", message),
+            DecodeError::TooLarge {
+                what,
+                length,
+                limit,
+            } => write!(f, "The {} of {} exceeds the limit {}.", what, length, limit),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(err: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// An error found when encoding a value in a serde format
+#[derive(Debug, PartialEq, Clone)]
+pub enum EncodeError {
+    /// JSON has no NaN or infinite numbers, which it would write as null
+    NonFinite,
+    /// The value is rejected by the serde format, with the message of the format
+    Format(String),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::NonFinite => write!(f, "JSON can not carry NaN or infinite numbers."),
+            EncodeError::Format(message) => write!(f, "Can not serialize: {}", message),
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+impl From<EncodeError> for io::Error {
+    fn from(err: EncodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// The options used when decoding payloads
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DecodeOptions {
+    pub limits: Limits,
+    /// Replace invalid UTF-8 sequences in strings with U+FFFD instead of
+    /// rejecting them, which may make different node names collide
+    pub lossy_utf8: bool,
+    /// The encoding agreed in the handshake
+    pub encoding: Encoding,
+}
 
 // A util trait used to get value from buffers
 trait TryGet: Sized {
-    fn try_get(buf: &mut Buf) -> Option<Self>;
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError>;
 }
 
 // A util trait used to put value to buffers
 trait Put {
-    fn put(&self, buf: &mut BytesMut);
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding);
 }
 
 #[cfg(test)]
 fn reconvert_test<T: TryGet + Put + PartialEq + std::fmt::Debug + Clone>(value: T) {
-    assert_eq!(Some(value.clone()), decode(encode(value.clone())));
+    for &encoding in &[Encoding::Native, Encoding::Compact { f32_weights: false }] {
+        let options = DecodeOptions {
+            encoding,
+            ..DecodeOptions::default()
+        };
+        assert_eq!(Ok(value.clone()), decode(encode(&value, encoding), &options));
+    }
 }
 
+/// Check that at least `length` bytes are left in the buffer
+fn ensure(buf: &dyn Buf, length: usize) -> Result<(), DecodeError> {
+    if buf.remaining() < length {
+        Err(DecodeError::Malformed)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the length read from the buffer does not exceed the limit
+fn check_limit(what: &'static str, length: usize, limit: usize) -> Result<(), DecodeError> {
+    if length > limit {
+        Err(DecodeError::TooLarge {
+            what,
+            length,
+            limit,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Put an unsigned LEB128 varint, seven bits in each byte from the lowest,
+/// with the high bit set when more bytes follow
+pub(crate) fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    buf.reserve(10);
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// Get an unsigned LEB128 varint, which must fit in `bits` bits
+pub(crate) fn try_get_varint(buf: &mut dyn Buf, bits: u32) -> Result<u64, DecodeError> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        ensure(buf, 1)?;
+        let byte = buf.get_u8();
+        let part = u64::from(byte & 0x7F);
+        if shift >= bits || (bits - shift < 7 && part >> (bits - shift) != 0) {
+            return Err(DecodeError::Malformed);
+        }
+        value |= part << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[test]
+fn varint_convert() {
+    for &value in &[0, 1, 0x7F, 0x80, 300, u64::from(u32::MAX), u64::MAX] {
+        let mut bytes = BytesMut::new();
+        put_varint(&mut bytes, value);
+        let mut cursor = std::io::Cursor::new(&bytes[..]);
+        assert_eq!(try_get_varint(&mut cursor, 64), Ok(value));
+        assert!(!cursor.has_remaining());
+    }
+    let mut bytes = BytesMut::new();
+    put_varint(&mut bytes, 300);
+    assert_eq!(&bytes[..], &[0xAC, 0x02]);
+    let mut bytes = BytesMut::new();
+    put_varint(&mut bytes, u64::from(u32::MAX) + 1);
+    assert_eq!(
+        try_get_varint(&mut std::io::Cursor::new(&bytes[..]), 32),
+        Err(DecodeError::Malformed)
+    );
+}
+
+// the tags of the weights in the compact encoding
+const INTEGER_WEIGHT: u8 = 0;
+const F32_WEIGHT: u8 = 1;
+const F64_WEIGHT: u8 = 2;
+
+/// The integers with larger magnitude may be rounded in f64
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
 impl TryGet for f64 {
-    fn try_get(buf: &mut Buf) -> Option<f64> {
-        if buf.remaining() < 8 {
-            None
-        } else {
-            Some(buf.get_f64_be())
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<f64, DecodeError> {
+        if !matches!(options.encoding, Encoding::Compact { .. }) {
+            ensure(buf, 8)?;
+            return Ok(buf.get_f64_be());
+        }
+        let kind = u8::try_get(buf, options)?;
+        match kind {
+            INTEGER_WEIGHT => {
+                let zigzag = try_get_varint(buf, 64)?;
+                Ok(((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64)) as f64)
+            }
+            F32_WEIGHT => {
+                ensure(buf, 4)?;
+                Ok(f64::from(buf.get_f32_be()))
+            }
+            F64_WEIGHT => {
+                ensure(buf, 8)?;
+                Ok(buf.get_f64_be())
+            }
+            _ => Err(DecodeError::UnknownTag(kind)),
         }
     }
 }
 
 impl Put for f64 {
-    fn put(&self, buf: &mut BytesMut) {
-        buf.reserve(8);
-        buf.put_f64_be(*self);
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        let value = *self;
+        let f32_weights = match encoding {
+            Encoding::Compact { f32_weights } => f32_weights,
+            _ => {
+                buf.reserve(8);
+                buf.put_f64_be(value);
+                return;
+            }
+        };
+        buf.reserve(9);
+        // -0.0 is not an integer here, so its sign survives
+        if value.fract() == 0.0
+            && value.abs() <= MAX_EXACT_INTEGER
+            && value.is_sign_positive() == (value >= 0.0)
+        {
+            let integer = value as i64;
+            buf.put_u8(INTEGER_WEIGHT);
+            put_varint(buf, ((integer << 1) ^ (integer >> 63)) as u64);
+        } else if f32_weights && (value as f32).is_finite() == value.is_finite() {
+            buf.put_u8(F32_WEIGHT);
+            buf.put_f32_be(value as f32);
+        } else {
+            buf.put_u8(F64_WEIGHT);
+            buf.put_f64_be(value);
+        }
     }
 }
 
 impl TryGet for u32 {
-    fn try_get(buf: &mut Buf) -> Option<u32> {
-        if buf.remaining() < 4 {
-            None
-        } else {
-            Some(buf.get_u32_be())
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<u32, DecodeError> {
+        match options.encoding {
+            Encoding::Compact { .. } => Ok(try_get_varint(buf, 32)? as u32),
+            _ => {
+                ensure(buf, 4)?;
+                Ok(buf.get_u32_be())
+            }
+        }
+    }
+}
+
+impl Put for u32 {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        match encoding {
+            Encoding::Compact { .. } => put_varint(buf, u64::from(*self)),
+            _ => {
+                buf.reserve(4);
+                buf.put_u32_be(*self);
+            }
+        }
+    }
+}
+
+impl TryGet for u64 {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<u64, DecodeError> {
+        match options.encoding {
+            Encoding::Compact { .. } => try_get_varint(buf, 64),
+            _ => {
+                ensure(buf, 8)?;
+                Ok(buf.get_u64_be())
+            }
+        }
+    }
+}
+
+impl Put for u64 {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        match encoding {
+            Encoding::Compact { .. } => put_varint(buf, *self),
+            _ => {
+                buf.reserve(8);
+                buf.put_u64_be(*self);
+            }
         }
     }
 }
 
 impl TryGet for u8 {
-    fn try_get(buf: &mut Buf) -> Option<u8> {
-        if buf.remaining() < 1 {
-            None
-        } else {
-            Some(buf.get_u8())
+    fn try_get(buf: &mut dyn Buf, _options: &DecodeOptions) -> Result<u8, DecodeError> {
+        ensure(buf, 1)?;
+        Ok(buf.get_u8())
+    }
+}
+
+impl TryGet for bool {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        match u8::try_get(buf, options)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::UnknownTag(tag)),
         }
     }
 }
 
+impl Put for bool {
+    fn put(&self, buf: &mut BytesMut, _encoding: Encoding) {
+        buf.reserve(1);
+        buf.put_u8(*self as u8);
+    }
+}
+
 impl Put for str {
-    fn put(&self, buf: &mut BytesMut) {
-        buf.reserve(4 + self.len());
-        buf.put_u32_be(self.len() as u32);
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        (self.len() as u32).put(buf, encoding);
+        buf.reserve(self.len());
         buf.put_slice(self.as_bytes());
     }
 }
 
 impl Put for String {
-    fn put(&self, buf: &mut BytesMut) {
-        self.as_str().put(buf);
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.as_str().put(buf, encoding);
+    }
+}
+
+/// Turn the bytes into a string, replacing the invalid sequences when `lossy` is true.
+/// The valid bytes are copied once, the lossy ones are copied again with the replacements.
+fn to_string(bytes: &[u8], lossy: bool) -> Result<String, DecodeError> {
+    if lossy {
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    } else {
+        let string = str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+        Ok(String::from(string))
+    }
+}
+
+/// Turn the copied bytes into a string without copying them again when they are valid
+fn into_string(bytes: Vec<u8>, lossy: bool) -> Result<String, DecodeError> {
+    match String::from_utf8(bytes) {
+        Ok(string) => Ok(string),
+        Err(err) if lossy => Ok(String::from_utf8_lossy(err.as_bytes()).into_owned()),
+        Err(_) => Err(DecodeError::InvalidUtf8),
     }
 }
 
 impl TryGet for String {
-    fn try_get(buf: &mut Buf) -> Option<String> {
-        let length = u32::try_get(buf)? as usize;
-        if buf.remaining() < length {
-            None
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<String, DecodeError> {
+        let length = u32::try_get(buf, options)? as usize;
+        check_limit("string length", length, options.limits.max_string_length)?;
+        ensure(buf, length)?;
+        // validate the bytes in place when they are contiguous, so they are copied only once
+        if buf.bytes().len() >= length {
+            let string = to_string(&buf.bytes()[..length], options.lossy_utf8)?;
+            buf.advance(length);
+            Ok(string)
         } else {
-            let mut buffer = BytesMut::new();
-            buffer.resize(length, 0x0);
+            let mut buffer = vec![0; length];
             buf.copy_to_slice(&mut buffer[..]);
-            Some(String::from_utf8_lossy(&buffer[..]).into_owned())
+            into_string(buffer, options.lossy_utf8)
         }
     }
 }
@@ -84,20 +370,53 @@ impl TryGet for String {
 #[test]
 fn string_convert() {
     let string = "Test";
-    reconvert_test(String::from(string))
+    reconvert_test(String::from(string));
+    reconvert_test(String::from("路径"));
+}
+
+#[test]
+fn utf8_convert() {
+    let mut bytes = BytesMut::new();
+    bytes.put_u32_be(3);
+    bytes.put_slice(&[b'a', 0xFF, b'b']);
+    let bytes = bytes.freeze();
+    assert_eq!(
+        decode::<String>(bytes.clone(), &DecodeOptions::default()),
+        Err(DecodeError::InvalidUtf8)
+    );
+    let lossy = DecodeOptions {
+        lossy_utf8: true,
+        ..DecodeOptions::default()
+    };
+    assert_eq!(
+        decode::<String>(bytes, &lossy),
+        Ok(String::from("a\u{FFFD}b"))
+    );
+    assert_eq!(
+        into_string(vec![b'a', 0xFF], false),
+        Err(DecodeError::InvalidUtf8)
+    );
+    assert_eq!(
+        into_string(vec![b'a', 0xFF], true),
+        Ok(String::from("a\u{FFFD}"))
+    );
+    assert_eq!(
+        into_string(Vec::from("路径"), false),
+        Ok(String::from("路径"))
+    );
 }
 
 impl<T> Put for Option<T>
 where
     T: Put,
 {
-    fn put(&self, buf: &mut BytesMut) {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
         buf.reserve(1);
         match self {
             None => buf.put_u8(0),
             Some(data) => {
                 buf.put_u8(1);
-                data.put(buf)
+                data.put(buf, encoding)
             }
         }
     }
@@ -107,11 +426,11 @@ impl<T> TryGet for Option<T>
 where
     T: TryGet,
 {
-    fn try_get(buf: &mut Buf) -> Option<Self> {
-        let present = u8::try_get(buf)?;
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let present = u8::try_get(buf, options)?;
         match present {
-            0 => Some(None),
-            _ => Some(Some(T::try_get(buf)?)),
+            0 => Ok(None),
+            _ => Ok(Some(T::try_get(buf, options)?)),
         }
     }
 }
@@ -127,16 +446,16 @@ where
     T: Put,
     E: Put,
 {
-    fn put(&self, buf: &mut BytesMut) {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
         buf.reserve(1);
         match self {
             Err(err) => {
                 buf.put_u8(0);
-                err.put(buf)
+                err.put(buf, encoding)
             }
             Ok(data) => {
                 buf.put_u8(1);
-                data.put(buf)
+                data.put(buf, encoding)
             }
         }
     }
@@ -147,11 +466,11 @@ where
     T: TryGet,
     E: TryGet,
 {
-    fn try_get(buf: &mut Buf) -> Option<Self> {
-        let ok = u8::try_get(buf)?;
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let ok = u8::try_get(buf, options)?;
         match ok {
-            0 => Some(Err(E::try_get(buf)?)),
-            _ => Some(Ok(T::try_get(buf)?)),
+            0 => Ok(Err(E::try_get(buf, options)?)),
+            _ => Ok(Ok(T::try_get(buf, options)?)),
         }
     }
 }
@@ -166,11 +485,10 @@ impl<T> Put for Vec<T>
 where
     T: Put,
 {
-    fn put(&self, buf: &mut BytesMut) {
-        buf.reserve(4);
-        buf.put_u32_be(self.len() as u32);
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        (self.len() as u32).put(buf, encoding);
         for item in self {
-            item.put(buf)
+            item.put(buf, encoding)
         }
     }
 }
@@ -178,16 +496,28 @@ impl<T> TryGet for Vec<T>
 where
     T: TryGet,
 {
-    fn try_get(buf: &mut Buf) -> Option<Self> {
-        let length = u32::try_get(buf)?;
-        let mut vec = Vec::with_capacity(length as usize);
-        for _ in 0..length {
-            vec.push(T::try_get(buf)?)
-        }
-        Some(vec)
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        try_get_vec(buf, options, "vector length", options.limits.max_vec_length)
     }
 }
 
+/// Read a vector whose length is checked against the given limit. The capacity is
+/// also bounded by the remaining bytes, so a forged length can not reserve much memory.
+fn try_get_vec<T: TryGet>(
+    buf: &mut dyn Buf,
+    options: &DecodeOptions,
+    what: &'static str,
+    limit: usize,
+) -> Result<Vec<T>, DecodeError> {
+    let length = u32::try_get(buf, options)? as usize;
+    check_limit(what, length, limit)?;
+    let mut vec = Vec::with_capacity(length.min(buf.remaining()));
+    for _ in 0..length {
+        vec.push(T::try_get(buf, options)?)
+    }
+    Ok(vec)
+}
+
 #[test]
 fn vec_convert() {
     reconvert_test::<Vec<String>>(vec![String::from("Test"), String::from("Test2")]);
@@ -200,11 +530,11 @@ where
     T2: Put,
     T3: Put,
 {
-    fn put(&self, buf: &mut BytesMut) {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
         let (first, second, third) = self;
-        first.put(buf);
-        second.put(buf);
-        third.put(buf);
+        first.put(buf, encoding);
+        second.put(buf, encoding);
+        third.put(buf, encoding);
     }
 }
 
@@ -214,11 +544,11 @@ where
     T2: TryGet,
     T3: TryGet,
 {
-    fn try_get(buf: &mut Buf) -> Option<Self> {
-        let first = T1::try_get(buf)?;
-        let second = T2::try_get(buf)?;
-        let third = T3::try_get(buf)?;
-        Some((first, second, third))
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let first = T1::try_get(buf, options)?;
+        let second = T2::try_get(buf, options)?;
+        let third = T3::try_get(buf, options)?;
+        Ok((first, second, third))
     }
 }
 
@@ -231,59 +561,1015 @@ fn triple_convert() {
     ));
 }
 
+impl<T1, T2, T3, T4> Put for (T1, T2, T3, T4)
+where
+    T1: Put,
+    T2: Put,
+    T3: Put,
+    T4: Put,
+{
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        let (first, second, third, fourth) = self;
+        first.put(buf, encoding);
+        second.put(buf, encoding);
+        third.put(buf, encoding);
+        fourth.put(buf, encoding);
+    }
+}
+
+impl<T1, T2, T3, T4> TryGet for (T1, T2, T3, T4)
+where
+    T1: TryGet,
+    T2: TryGet,
+    T3: TryGet,
+    T4: TryGet,
+{
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let first = T1::try_get(buf, options)?;
+        let second = T2::try_get(buf, options)?;
+        let third = T3::try_get(buf, options)?;
+        let fourth = T4::try_get(buf, options)?;
+        Ok((first, second, third, fourth))
+    }
+}
+
+#[test]
+fn quadruple_convert() {
+    reconvert_test((String::from("Test"), String::from("Test2"), 1.0, -1.0));
+}
+
 impl Put for NegativeCycle {
-    fn put(&self, _buf: &mut BytesMut) {}
+    fn put(&self, _buf: &mut BytesMut, _encoding: Encoding) {}
 }
 
 impl TryGet for NegativeCycle {
-    fn try_get(_buf: &mut Buf) -> Option<Self> {
-        Some(NegativeCycle())
+    fn try_get(_buf: &mut dyn Buf, _options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(NegativeCycle())
+    }
+}
+
+impl Put for Cycle {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.0.put(buf, encoding)
+    }
+}
+
+impl TryGet for Cycle {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(Cycle(Vec::try_get(buf, options)?))
+    }
+}
+
+impl Put for InvalidWeight {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.0.put(buf, encoding);
+        self.1.put(buf, encoding);
+        self.2.put(buf, encoding);
+    }
+}
+
+impl TryGet for InvalidWeight {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let (start, end, weight) = TryGet::try_get(buf, options)?;
+        Ok(InvalidWeight(start, end, weight))
+    }
+}
+
+impl Put for MaxFlow {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.value.put(buf, encoding);
+        self.flows.put(buf, encoding);
+        self.source_side.put(buf, encoding);
+        self.sink_side.put(buf, encoding);
+    }
+}
+
+impl TryGet for MaxFlow {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(MaxFlow {
+            value: f64::try_get(buf, options)?,
+            flows: Vec::try_get(buf, options)?,
+            source_side: Vec::try_get(buf, options)?,
+            sink_side: Vec::try_get(buf, options)?,
+        })
+    }
+}
+
+impl Put for SpanningForest {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.edges.put(buf, encoding);
+        self.total_weight.put(buf, encoding);
+        self.tree_count.put(buf, encoding);
+    }
+}
+
+impl TryGet for SpanningForest {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(SpanningForest {
+            edges: Vec::try_get(buf, options)?,
+            total_weight: f64::try_get(buf, options)?,
+            tree_count: u32::try_get(buf, options)?,
+        })
+    }
+}
+
+impl Put for MinCostFlow {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.value.put(buf, encoding);
+        self.cost.put(buf, encoding);
+        self.flows.put(buf, encoding);
+    }
+}
+
+impl TryGet for MinCostFlow {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(MinCostFlow {
+            value: f64::try_get(buf, options)?,
+            cost: f64::try_get(buf, options)?,
+            flows: Vec::try_get(buf, options)?,
+        })
+    }
+}
+
+impl Put for MinCostFlowError {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        buf.reserve(1);
+        match self {
+            MinCostFlowError::InvalidCapacity(edge) => {
+                buf.put_u8(0);
+                edge.put(buf, encoding);
+            }
+            MinCostFlowError::InvalidCost(edge) => {
+                buf.put_u8(1);
+                edge.put(buf, encoding);
+            }
+            MinCostFlowError::NegativeCycle => buf.put_u8(2),
+            MinCostFlowError::InvalidAmount(amount) => {
+                buf.put_u8(3);
+                amount.put(buf, encoding);
+            }
+        }
+    }
+}
+
+impl TryGet for MinCostFlowError {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let kind = u8::try_get(buf, options)?;
+        match kind {
+            0 => Ok(MinCostFlowError::InvalidCapacity(InvalidWeight::try_get(
+                buf, options,
+            )?)),
+            1 => Ok(MinCostFlowError::InvalidCost(InvalidWeight::try_get(
+                buf, options,
+            )?)),
+            2 => Ok(MinCostFlowError::NegativeCycle),
+            3 => Ok(MinCostFlowError::InvalidAmount(f64::try_get(buf, options)?)),
+            _ => Err(DecodeError::UnknownTag(kind)),
+        }
+    }
+}
+
+impl Put for Health {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.live.put(buf, encoding);
+        self.ready.put(buf, encoding);
+    }
+}
+
+impl TryGet for Health {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(Health {
+            live: bool::try_get(buf, options)?,
+            ready: bool::try_get(buf, options)?,
+        })
+    }
+}
+
+impl Put for ServerInfo {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.version.put(buf, encoding);
+        self.min_protocol_version.put(buf, encoding);
+        self.max_protocol_version.put(buf, encoding);
+        self.uptime.put(buf, encoding);
+        self.methods.put(buf, encoding);
+    }
+}
+
+impl TryGet for ServerInfo {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(ServerInfo {
+            version: String::try_get(buf, options)?,
+            min_protocol_version: u32::try_get(buf, options)?,
+            max_protocol_version: u32::try_get(buf, options)?,
+            uptime: u64::try_get(buf, options)?,
+            methods: Vec::try_get(buf, options)?,
+        })
     }
 }
 
 impl Put for Graph {
-    fn put(&self, buf: &mut BytesMut) {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
         let vec: Vec<_> = self.clone().into();
-        vec.put(buf);
+        vec.put(buf, encoding);
     }
 }
 
 impl TryGet for Graph {
-    fn try_get(buf: &mut Buf) -> Option<Self> {
-        let vec: Vec<(String, String, f64)> = Vec::try_get(buf)?;
-        Some(Graph::from(vec))
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let vec: Vec<(String, String, f64)> =
+            try_get_vec(buf, options, "graph size", options.limits.max_graph_edges)?;
+        Ok(Graph::from(vec))
+    }
+}
+
+impl Put for FlowNetwork {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        let vec: Vec<_> = self.clone().into();
+        vec.put(buf, encoding);
+    }
+}
+
+impl TryGet for FlowNetwork {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let vec: Vec<(String, String, f64, f64)> =
+            try_get_vec(buf, options, "graph size", options.limits.max_graph_edges)?;
+        Ok(FlowNetwork::from(vec))
+    }
+}
+
+impl Put for Request {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        buf.reserve(1);
+        match self {
+            Request::ShortestPath {
+                graph,
+                start,
+                end,
+                max_hops,
+            } => {
+                buf.put_u8(0);
+                graph.put(buf, encoding);
+                start.put(buf, encoding);
+                end.put(buf, encoding);
+                max_hops.put(buf, encoding);
+            }
+            Request::StronglyConnectedComponents { graph } => {
+                buf.put_u8(1);
+                graph.put(buf, encoding);
+            }
+            Request::TopologicalSort { graph } => {
+                buf.put_u8(2);
+                graph.put(buf, encoding);
+            }
+            Request::Reachable { graph, node } => {
+                buf.put_u8(3);
+                graph.put(buf, encoding);
+                node.put(buf, encoding);
+            }
+            Request::CoReachable { graph, node } => {
+                buf.put_u8(4);
+                graph.put(buf, encoding);
+                node.put(buf, encoding);
+            }
+            Request::MaximumFlow {
+                graph,
+                source,
+                sink,
+            } => {
+                buf.put_u8(5);
+                graph.put(buf, encoding);
+                source.put(buf, encoding);
+                sink.put(buf, encoding);
+            }
+            Request::MinimumSpanningForest { graph } => {
+                buf.put_u8(6);
+                graph.put(buf, encoding);
+            }
+            Request::MinimumCostFlow {
+                network,
+                source,
+                sink,
+                amount,
+            } => {
+                buf.put_u8(7);
+                network.put(buf, encoding);
+                source.put(buf, encoding);
+                sink.put(buf, encoding);
+                amount.put(buf, encoding);
+            }
+            Request::Health {} => buf.put_u8(8),
+            Request::ServerInfo {} => buf.put_u8(9),
+        }
+    }
+}
+
+impl TryGet for Request {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let method = u8::try_get(buf, options)?;
+        match method {
+            0 => {
+                let request = Request::ShortestPath {
+                    graph: Graph::try_get(buf, options)?,
+                    start: String::try_get(buf, options)?,
+                    end: String::try_get(buf, options)?,
+                    max_hops: Option::try_get(buf, options)?,
+                };
+                check_max_hops(&request, &options.limits)?;
+                Ok(request)
+            }
+            1 => Ok(Request::StronglyConnectedComponents {
+                graph: Graph::try_get(buf, options)?,
+            }),
+            2 => Ok(Request::TopologicalSort {
+                graph: Graph::try_get(buf, options)?,
+            }),
+            3 => Ok(Request::Reachable {
+                graph: Graph::try_get(buf, options)?,
+                node: String::try_get(buf, options)?,
+            }),
+            4 => Ok(Request::CoReachable {
+                graph: Graph::try_get(buf, options)?,
+                node: String::try_get(buf, options)?,
+            }),
+            5 => Ok(Request::MaximumFlow {
+                graph: Graph::try_get(buf, options)?,
+                source: String::try_get(buf, options)?,
+                sink: String::try_get(buf, options)?,
+            }),
+            6 => Ok(Request::MinimumSpanningForest {
+                graph: Graph::try_get(buf, options)?,
+            }),
+            7 => Ok(Request::MinimumCostFlow {
+                network: FlowNetwork::try_get(buf, options)?,
+                source: String::try_get(buf, options)?,
+                sink: String::try_get(buf, options)?,
+                amount: Option::try_get(buf, options)?,
+            }),
+            8 => Ok(Request::Health {}),
+            9 => Ok(Request::ServerInfo {}),
+            _ => Err(DecodeError::UnknownTag(method)),
+        }
+    }
+}
+
+#[test]
+fn request_convert() {
+    let graph = Graph::from_edges(&[("a", "b", 1.0)]);
+    reconvert_test(Request::ShortestPath {
+        graph: graph.clone(),
+        start: String::from("a"),
+        end: String::from("b"),
+        max_hops: Some(3),
+    });
+    reconvert_test(Request::StronglyConnectedComponents {
+        graph: graph.clone(),
+    });
+    reconvert_test(Request::TopologicalSort {
+        graph: graph.clone(),
+    });
+    reconvert_test(Request::Reachable {
+        graph: graph.clone(),
+        node: String::from("a"),
+    });
+    reconvert_test(Request::CoReachable {
+        graph: graph.clone(),
+        node: String::from("b"),
+    });
+    reconvert_test(Request::MinimumSpanningForest {
+        graph: graph.clone(),
+    });
+    reconvert_test(Request::MinimumCostFlow {
+        network: FlowNetwork::from_edges(&[("a", "b", 1.0, 2.0)]),
+        source: String::from("a"),
+        sink: String::from("b"),
+        amount: Some(1.0),
+    });
+    reconvert_test(Request::MaximumFlow {
+        graph,
+        source: String::from("a"),
+        sink: String::from("b"),
+    });
+    reconvert_test(Request::Health {});
+    reconvert_test(Request::ServerInfo {});
+}
+
+/// The tag of `Respond::Error`, apart from the tags of the methods
+const ERROR_RESPOND: u8 = 0xFF;
+
+impl Put for ServerError {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        buf.reserve(1);
+        match self {
+            ServerError::Corrupted => buf.put_u8(0),
+            ServerError::Forbidden(reason) => {
+                buf.put_u8(1);
+                reason.put(buf, encoding);
+            }
+            ServerError::GoingAway => buf.put_u8(2),
+            ServerError::Overloaded(reason) => {
+                buf.put_u8(3);
+                reason.put(buf, encoding);
+            }
+            ServerError::Invalid(reason) => {
+                buf.put_u8(4);
+                reason.put(buf, encoding);
+            }
+        }
+    }
+}
+
+impl TryGet for ServerError {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let kind = u8::try_get(buf, options)?;
+        match kind {
+            0 => Ok(ServerError::Corrupted),
+            1 => Ok(ServerError::Forbidden(String::try_get(buf, options)?)),
+            2 => Ok(ServerError::GoingAway),
+            3 => Ok(ServerError::Overloaded(String::try_get(buf, options)?)),
+            4 => Ok(ServerError::Invalid(String::try_get(buf, options)?)),
+            _ => Err(DecodeError::UnknownTag(kind)),
+        }
+    }
+}
+
+impl Put for Respond {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        buf.reserve(1);
+        match self {
+            Respond::ShortestPath(result) => {
+                buf.put_u8(0);
+                result.put(buf, encoding);
+            }
+            Respond::StronglyConnectedComponents(components) => {
+                buf.put_u8(1);
+                components.put(buf, encoding);
+            }
+            Respond::TopologicalSort(result) => {
+                buf.put_u8(2);
+                result.put(buf, encoding);
+            }
+            Respond::Reachable(nodes) => {
+                buf.put_u8(3);
+                nodes.put(buf, encoding);
+            }
+            Respond::CoReachable(nodes) => {
+                buf.put_u8(4);
+                nodes.put(buf, encoding);
+            }
+            Respond::MaximumFlow(result) => {
+                buf.put_u8(5);
+                result.put(buf, encoding);
+            }
+            Respond::MinimumSpanningForest(result) => {
+                buf.put_u8(6);
+                result.put(buf, encoding);
+            }
+            Respond::MinimumCostFlow(result) => {
+                buf.put_u8(7);
+                result.put(buf, encoding);
+            }
+            Respond::Health(health) => {
+                buf.put_u8(8);
+                health.put(buf, encoding);
+            }
+            Respond::ServerInfo(info) => {
+                buf.put_u8(9);
+                info.put(buf, encoding);
+            }
+            Respond::Error(err) => {
+                buf.put_u8(ERROR_RESPOND);
+                err.put(buf, encoding);
+            }
+        }
+    }
+}
+
+impl TryGet for Respond {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let method = u8::try_get(buf, options)?;
+        match method {
+            0 => Ok(Respond::ShortestPath(Result::try_get(buf, options)?)),
+            1 => Ok(Respond::StronglyConnectedComponents(Vec::try_get(
+                buf, options,
+            )?)),
+            2 => Ok(Respond::TopologicalSort(Result::try_get(buf, options)?)),
+            3 => Ok(Respond::Reachable(Option::try_get(buf, options)?)),
+            4 => Ok(Respond::CoReachable(Option::try_get(buf, options)?)),
+            5 => Ok(Respond::MaximumFlow(Result::try_get(buf, options)?)),
+            6 => Ok(Respond::MinimumSpanningForest(Result::try_get(
+                buf, options,
+            )?)),
+            7 => Ok(Respond::MinimumCostFlow(Result::try_get(buf, options)?)),
+            8 => Ok(Respond::Health(Health::try_get(buf, options)?)),
+            9 => Ok(Respond::ServerInfo(ServerInfo::try_get(buf, options)?)),
+            ERROR_RESPOND => Ok(Respond::Error(ServerError::try_get(buf, options)?)),
+            _ => Err(DecodeError::UnknownTag(method)),
+        }
+    }
+}
+
+#[test]
+fn respond_convert() {
+    let nodes = vec![String::from("a"), String::from("b")];
+    reconvert_test(Respond::ShortestPath(Ok(Some(nodes.clone()))));
+    reconvert_test(Respond::ShortestPath(Err(NegativeCycle())));
+    reconvert_test(Respond::StronglyConnectedComponents(vec![nodes.clone()]));
+    reconvert_test(Respond::TopologicalSort(Ok(nodes.clone())));
+    reconvert_test(Respond::TopologicalSort(Err(Cycle(nodes.clone()))));
+    reconvert_test(Respond::Reachable(Some(nodes.clone())));
+    reconvert_test(Respond::CoReachable(None));
+    reconvert_test(Respond::MaximumFlow(Ok(Some(MaxFlow {
+        value: 1.0,
+        flows: vec![(String::from("a"), String::from("b"), 1.0)],
+        source_side: vec![String::from("a")],
+        sink_side: vec![String::from("b")],
+    }))));
+    reconvert_test(Respond::MinimumSpanningForest(Ok(SpanningForest {
+        edges: vec![(String::from("a"), String::from("b"), 1.0)],
+        total_weight: 1.0,
+        tree_count: 1,
+    })));
+    reconvert_test(Respond::MinimumCostFlow(Ok(Some(MinCostFlow {
+        value: 1.0,
+        cost: 2.0,
+        flows: vec![(String::from("a"), String::from("b"), 1.0)],
+    }))));
+    reconvert_test(Respond::MinimumCostFlow(Err(
+        MinCostFlowError::NegativeCycle,
+    )));
+    reconvert_test(Respond::MinimumCostFlow(Err(
+        MinCostFlowError::InvalidAmount(-1.0),
+    )));
+    reconvert_test(Respond::Error(ServerError::Corrupted));
+    reconvert_test(Respond::Error(ServerError::Forbidden(String::from(
+        "Method not allowed.",
+    ))));
+    reconvert_test(Respond::Error(ServerError::GoingAway));
+    reconvert_test(Respond::Error(ServerError::Overloaded(String::from(
+        "Too many requests.",
+    ))));
+    reconvert_test(Respond::Error(ServerError::Invalid(String::from(
+        "Malformed payload.",
+    ))));
+    reconvert_test(Respond::MaximumFlow(Err(InvalidWeight(
+        String::from("a"),
+        String::from("b"),
+        -1.0,
+    ))));
+    reconvert_test(Respond::Health(Health {
+        live: true,
+        ready: false,
+    }));
+    reconvert_test(Respond::ServerInfo(ServerInfo {
+        version: String::from("0.0.0"),
+        min_protocol_version: 1,
+        max_protocol_version: 1,
+        uptime: 3600,
+        methods: vec![String::from("health")],
+    }));
+}
+
+impl Put for Features {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.methods.put(buf, encoding);
+        self.encodings.put(buf, encoding);
+        self.compressions.put(buf, encoding);
+        self.checksums.put(buf, encoding);
+    }
+}
+
+impl TryGet for Features {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(Features {
+            methods: Vec::try_get(buf, options)?,
+            encodings: Vec::try_get(buf, options)?,
+            compressions: Vec::try_get(buf, options)?,
+            checksums: Vec::try_get(buf, options)?,
+        })
+    }
+}
+
+impl Put for Credentials {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        buf.reserve(1);
+        match self {
+            Credentials::Token(token) => {
+                buf.put_u8(0);
+                token.put(buf, encoding);
+            }
+            Credentials::Hmac {
+                principal,
+                timestamp,
+                nonce,
+                signature,
+            } => {
+                buf.put_u8(1);
+                principal.put(buf, encoding);
+                timestamp.put(buf, encoding);
+                nonce.put(buf, encoding);
+                signature.put(buf, encoding);
+            }
+        }
+    }
+}
+
+impl TryGet for Credentials {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let kind = u8::try_get(buf, options)?;
+        match kind {
+            0 => Ok(Credentials::Token(String::try_get(buf, options)?)),
+            1 => Ok(Credentials::Hmac {
+                principal: String::try_get(buf, options)?,
+                timestamp: u64::try_get(buf, options)?,
+                nonce: String::try_get(buf, options)?,
+                signature: String::try_get(buf, options)?,
+            }),
+            _ => Err(DecodeError::UnknownTag(kind)),
+        }
+    }
+}
+
+impl Put for Hello {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.min_version.put(buf, encoding);
+        self.max_version.put(buf, encoding);
+        self.features.put(buf, encoding);
+        // the hello without credentials is the same as before they were added
+        if self.credentials.is_some() {
+            self.credentials.put(buf, encoding);
+        }
+    }
+}
+
+impl TryGet for Hello {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(Hello {
+            min_version: u32::try_get(buf, options)?,
+            max_version: u32::try_get(buf, options)?,
+            features: Features::try_get(buf, options)?,
+            credentials: if buf.has_remaining() {
+                Option::try_get(buf, options)?
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl Put for Agreement {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        self.version.put(buf, encoding);
+        self.encoding.put(buf, encoding);
+        self.compression.put(buf, encoding);
+        self.checksum.put(buf, encoding);
+        self.methods.put(buf, encoding);
+    }
+}
+
+impl TryGet for Agreement {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        Ok(Agreement {
+            version: u32::try_get(buf, options)?,
+            encoding: String::try_get(buf, options)?,
+            compression: String::try_get(buf, options)?,
+            checksum: String::try_get(buf, options)?,
+            methods: Vec::try_get(buf, options)?,
+        })
+    }
+}
+
+impl Put for Welcome {
+    fn put(&self, buf: &mut BytesMut, encoding: Encoding) {
+        buf.reserve(1);
+        match self {
+            Welcome::Reject(reason) => {
+                buf.put_u8(0);
+                reason.put(buf, encoding);
+            }
+            Welcome::Accept(agreement) => {
+                buf.put_u8(1);
+                agreement.put(buf, encoding);
+            }
+        }
     }
 }
 
-fn encode<T: Put>(value: T) -> Bytes {
+impl TryGet for Welcome {
+    fn try_get(buf: &mut dyn Buf, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let accept = u8::try_get(buf, options)?;
+        match accept {
+            0 => Ok(Welcome::Reject(String::try_get(buf, options)?)),
+            _ => Ok(Welcome::Accept(Agreement::try_get(buf, options)?)),
+        }
+    }
+}
+
+#[test]
+fn handshake_convert() {
+    reconvert_test(Hello::new(Features::supported()));
+    reconvert_test(Hello {
+        credentials: Some(Credentials::Token(String::from("token"))),
+        ..Hello::new(Features::supported())
+    });
+    reconvert_test(Hello {
+        credentials: Some(Credentials::hmac("tenant", b"secret", 1_700_000_000)),
+        ..Hello::new(Features::supported())
+    });
+    reconvert_test(Welcome::Accept(Agreement {
+        version: PROTOCOL_VERSION,
+        encoding: String::from(NATIVE_ENCODING),
+        compression: String::from(NO_COMPRESSION),
+        checksum: String::from(NO_CHECKSUM),
+        methods: vec![String::from("shortest_path")],
+    }));
+    reconvert_test(Welcome::Reject(String::from("Rejected.")));
+}
+
+fn encode<T: Put>(value: &T, encoding: Encoding) -> Bytes {
     let mut bytes = BytesMut::new();
-    value.put(&mut bytes);
+    value.put(&mut bytes, encoding);
     bytes.freeze()
 }
 
-pub fn encode_request(graph: &Graph, start: &str, end: &str) -> Bytes {
-    encode((graph.clone(), String::from(start), String::from(end)))
+/// Encode a request, which fails when the serde format can not carry it
+pub fn encode_request(request: &Request, encoding: Encoding) -> Result<Bytes, EncodeError> {
+    if encoding == Encoding::Json && !request.is_finite() {
+        return Err(EncodeError::NonFinite);
+    }
+    serialize(request, encoding).unwrap_or_else(|| Ok(encode(request, encoding)))
 }
 
-pub fn encode_respond(result: Result<Option<Vec<String>>, NegativeCycle>) -> Bytes {
-    encode(result)
+/// Encode a respond. A respond the serde format can not carry is replaced by
+/// an invalid request error respond telling why, which any format can carry.
+pub fn encode_respond(respond: &Respond, encoding: Encoding) -> Bytes {
+    let result = if encoding == Encoding::Json && !respond.is_finite() {
+        Some(Err(EncodeError::NonFinite))
+    } else {
+        serialize(respond, encoding)
+    };
+    match result {
+        Some(Ok(bytes)) => bytes,
+        Some(Err(err)) => {
+            let invalid = Respond::Error(ServerError::Invalid(err.to_string()));
+            serialize(&invalid, encoding)
+                .and_then(Result::ok)
+                .unwrap_or_default()
+        }
+        None => encode(respond, encoding),
+    }
 }
 
-fn decode<T: TryGet>(bytes: Bytes) -> Option<T> {
+/// The handshake packets always use the native encoding
+pub fn encode_hello(hello: &Hello) -> Bytes {
+    encode(hello, Encoding::Native)
+}
+
+pub fn encode_welcome(welcome: &Welcome) -> Bytes {
+    encode(welcome, Encoding::Native)
+}
+
+/// Check that the hop limit of a shortest path request does not exceed the limit
+pub fn check_max_hops(request: &Request, limits: &Limits) -> Result<(), DecodeError> {
+    match request {
+        Request::ShortestPath {
+            max_hops: Some(max_hops),
+            ..
+        } => check_limit("hop limit", *max_hops as usize, limits.max_hops),
+        _ => Ok(()),
+    }
+}
+
+fn decode<T: TryGet>(bytes: Bytes, options: &DecodeOptions) -> Result<T, DecodeError> {
     use std::io::Cursor;
     let mut cursor = Cursor::new(&*bytes);
-    let value = T::try_get(&mut cursor);
+    let value = T::try_get(&mut cursor, options)?;
     if !cursor.has_remaining() {
-        value
+        Ok(value)
     } else {
-        None
+        Err(DecodeError::Malformed)
     }
 }
 
-pub fn decode_request(bytes: Bytes) -> Option<(Graph, String, String)> {
-    decode(bytes)
+/// Decode a request. The serde formats are only limited by the frame length while
+/// parsing, so the graph size and the hop limit are checked afterwards and invalid UTF-8
+/// is always rejected.
+pub fn decode_request(bytes: Bytes, options: &DecodeOptions) -> Result<Request, DecodeError> {
+    match deserialize::<Request>(&bytes, options.encoding) {
+        Some(request) => {
+            let request = request?;
+            let limit = options.limits.max_graph_edges;
+            check_limit("graph size", request.graph_size(), limit)?;
+            check_max_hops(&request, &options.limits)?;
+            Ok(request)
+        }
+        None => decode(bytes, options),
+    }
 }
 
-pub fn decode_respond(bytes: Bytes) -> Option<Result<Option<Vec<String>>, NegativeCycle>> {
-    decode(bytes)
+pub fn decode_respond(bytes: Bytes, options: &DecodeOptions) -> Result<Respond, DecodeError> {
+    match deserialize(&bytes, options.encoding) {
+        Some(respond) => respond,
+        None => decode(bytes, options),
+    }
+}
+
+/// Decode a handshake packet with the native encoding, whatever `options.encoding` is
+fn decode_native<T: TryGet>(bytes: Bytes, options: &DecodeOptions) -> Result<T, DecodeError> {
+    let options = DecodeOptions {
+        encoding: Encoding::Native,
+        ..options.clone()
+    };
+    decode(bytes, &options)
+}
+
+pub fn decode_hello(bytes: Bytes, options: &DecodeOptions) -> Result<Hello, DecodeError> {
+    decode_native(bytes, options)
+}
+
+pub fn decode_welcome(bytes: Bytes, options: &DecodeOptions) -> Result<Welcome, DecodeError> {
+    decode_native(bytes, options)
+}
+
+#[test]
+fn limits_test() {
+    let limits = DecodeOptions {
+        limits: Limits {
+            max_string_length: 4,
+            max_vec_length: 2,
+            max_graph_edges: 1,
+            ..Limits::default()
+        },
+        ..DecodeOptions::default()
+    };
+    let too_large = |what, length, limit| {
+        Err::<Request, DecodeError>(DecodeError::TooLarge {
+            what,
+            length,
+            limit,
+        })
+    };
+    let request = |graph: Graph, node: &str| {
+        encode(
+            &Request::Reachable {
+                graph,
+                node: String::from(node),
+            },
+            Encoding::Native,
+        )
+    };
+    let graph = Graph::from_edges(&[("a", "b", 1.0)]);
+    assert!(decode::<Request>(request(graph.clone(), "a"), &limits).is_ok());
+    assert_eq!(
+        decode(request(graph.clone(), "abcde"), &limits),
+        too_large("string length", 5, 4)
+    );
+    let graph = Graph::from_edges(&[("a", "b", 1.0), ("b", "c", 1.0)]);
+    assert_eq!(
+        decode(request(graph.clone(), "a"), &limits),
+        too_large("graph size", 2, 1)
+    );
+    // the serde formats check the graph size after parsing
+    let json = DecodeOptions {
+        encoding: Encoding::Json,
+        ..limits.clone()
+    };
+    let payload = encode_request(
+        &Request::Reachable {
+            graph,
+            node: String::from("a"),
+        },
+        Encoding::Json,
+    )
+    .unwrap();
+    assert_eq!(
+        decode_request(payload, &json),
+        too_large("graph size", 2, 1)
+    );
+    // a huge hop limit would run that many rounds around a negative cycle
+    let cycle = Graph::from_edges(&[("a", "b", 1.0), ("b", "a", -2.0)]);
+    let request = Request::ShortestPath {
+        graph: cycle,
+        start: String::from("a"),
+        end: String::from("b"),
+        max_hops: Some(u32::MAX),
+    };
+    let hop_limit = too_large("hop limit", u32::MAX as usize, Limits::default().max_hops);
+    let json = DecodeOptions {
+        encoding: Encoding::Json,
+        ..DecodeOptions::default()
+    };
+    for options in &[DecodeOptions::default(), json] {
+        let payload = encode_request(&request, options.encoding).unwrap();
+        assert_eq!(decode_request(payload, options), hop_limit);
+    }
+    let nodes = vec![String::from("a"); 3];
+    assert_eq!(
+        decode::<Respond>(
+            encode(&Respond::Reachable(Some(nodes)), Encoding::Native),
+            &limits
+        ),
+        Err(DecodeError::TooLarge {
+            what: "vector length",
+            length: 3,
+            limit: 2
+        })
+    );
+    // a forged length far beyond the payload must fail without reserving memory
+    let mut bytes = BytesMut::new();
+    bytes.put_u8(3);
+    bytes.put_u32_be(u32::MAX);
+    assert_eq!(
+        decode::<Request>(bytes.freeze(), &DecodeOptions::default()),
+        too_large(
+            "graph size",
+            u32::MAX as usize,
+            Limits::default().max_graph_edges
+        )
+    );
+    let mut bytes = BytesMut::new();
+    bytes.put_u32_be(1000);
+    assert_eq!(
+        decode::<Vec<f64>>(bytes.freeze(), &DecodeOptions::default()),
+        Err(DecodeError::Malformed)
+    );
+}
+
+#[test]
+fn compact_convert() {
+    let compact = Encoding::Compact { f32_weights: false };
+    let compact_f32 = Encoding::Compact { f32_weights: true };
+    let options = |encoding| DecodeOptions {
+        encoding,
+        ..DecodeOptions::default()
+    };
+    for &weight in &[
+        0.0,
+        -0.0,
+        1.0,
+        -7.0,
+        1e15,
+        0.1,
+        1e300,
+        f64::INFINITY,
+        f64::MIN,
+    ] {
+        let bytes = encode(&weight, compact);
+        assert_eq!(decode::<f64>(bytes, &options(compact)), Ok(weight));
+    }
+    let reconvert_f32 =
+        |weight: f64| decode::<f64>(encode(&weight, compact_f32), &options(compact_f32)).unwrap();
+    assert_eq!(reconvert_f32(0.1), f64::from(0.1f32));
+    assert_eq!(reconvert_f32(1e15), 1e15);
+    // the values out of the range of f32 are sent as f64
+    assert_eq!(reconvert_f32(1e300), 1e300);
+    assert_eq!(reconvert_f32(f64::INFINITY), f64::INFINITY);
+    assert!(decode::<f64>(encode(&f64::NAN, compact), &options(compact))
+        .unwrap()
+        .is_nan());
+    assert_eq!(&encode(&-1.0, compact)[..], &[INTEGER_WEIGHT, 0x01]);
+    let graph = Graph::from_edges(&[("a", "b", 1.0), ("b", "c", 2.0), ("a", "c", 4.0)]);
+    let request = Request::ShortestPath {
+        graph,
+        start: String::from("a"),
+        end: String::from("c"),
+        max_hops: Some(2),
+    };
+    let native = encode(&request, Encoding::Native);
+    let small = encode(&request, compact);
+    assert!(small.len() * 2 < native.len());
+    assert_eq!(decode(small, &options(compact)), Ok(request));
+}
+
+#[test]
+fn non_finite_convert() {
+    let options = |encoding| DecodeOptions {
+        encoding,
+        ..DecodeOptions::default()
+    };
+    let request = Request::MinimumCostFlow {
+        network: FlowNetwork::from_edges(&[("s", "t", 1.0, 1.0)]),
+        source: String::from("s"),
+        sink: String::from("t"),
+        amount: Some(f64::NAN),
+    };
+    assert_eq!(
+        encode_request(&request, Encoding::Json),
+        Err(EncodeError::NonFinite)
+    );
+    let payload = encode_request(&request, Encoding::MessagePack).unwrap();
+    assert!(decode_request(payload, &options(Encoding::MessagePack)).is_ok());
+    // the null written for them by other JSON writers is rejected
+    let payload = Bytes::from(&br#"{"topological_sort":{"graph":[["a","b",null]]}}"#[..]);
+    assert!(matches!(
+        decode_request(payload, &options(Encoding::Json)),
+        Err(DecodeError::Format(_))
+    ));
+    let respond = Respond::MinimumCostFlow(Err(MinCostFlowError::InvalidAmount(f64::NAN)));
+    let payload = encode_respond(&respond, Encoding::Json);
+    assert_eq!(
+        decode_respond(payload, &options(Encoding::Json)),
+        Ok(Respond::Error(ServerError::Invalid(
+            EncodeError::NonFinite.to_string()
+        )))
+    );
 }
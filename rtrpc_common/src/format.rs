@@ -0,0 +1,78 @@
+use crate::convert::{DecodeError, EncodeError};
+#[cfg(test)]
+use crate::graph::*;
+use crate::handshake::Encoding;
+#[cfg(test)]
+use crate::request::*;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Serialize the value in the serde format of the encoding,
+/// returns `None` for the binary encodings which are handled by `convert`
+pub(crate) fn serialize<T: Serialize>(
+    value: &T,
+    encoding: Encoding,
+) -> Option<Result<Bytes, EncodeError>> {
+    let format = |err: &dyn ToString| EncodeError::Format(err.to_string());
+    let result = match encoding {
+        Encoding::Json => serde_json::to_vec(value).map_err(|err| format(&err)),
+        // the fields are written with their names, like JSON
+        Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| format(&err)),
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(value, &mut bytes)
+                .map(|_| bytes)
+                .map_err(|err| format(&err))
+        }
+        Encoding::Native | Encoding::Compact { .. } => return None,
+    };
+    Some(result.map(Bytes::from))
+}
+
+/// Deserialize the value in the serde format of the encoding,
+/// returns `None` for the binary encodings which are handled by `convert`
+pub(crate) fn deserialize<T: DeserializeOwned>(
+    bytes: &[u8],
+    encoding: Encoding,
+) -> Option<Result<T, DecodeError>> {
+    let format = |err: &dyn ToString| DecodeError::Format(err.to_string());
+    let result = match encoding {
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|err| format(&err)),
+        Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| format(&err)),
+        Encoding::Cbor => ciborium::from_reader(bytes).map_err(|err| format(&err)),
+        Encoding::Native | Encoding::Compact { .. } => return None,
+    };
+    Some(result)
+}
+
+#[test]
+fn format_convert() {
+    let graph = Graph::from_edges(&[("a", "b", 1.5)]);
+    let request = Request::ShortestPath {
+        graph: graph.clone(),
+        start: String::from("a"),
+        end: String::from("b"),
+        max_hops: None,
+    };
+    let respond = Respond::MaximumFlow(Ok(Some(MaxFlow {
+        value: 1.5,
+        flows: vec![(String::from("a"), String::from("b"), 1.5)],
+        source_side: vec![String::from("a")],
+        sink_side: vec![String::from("b")],
+    })));
+    for &encoding in &[Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+        let bytes = serialize(&request, encoding).unwrap().unwrap();
+        assert_eq!(deserialize(&bytes, encoding), Some(Ok(request.clone())));
+        let bytes = serialize(&respond, encoding).unwrap().unwrap();
+        assert_eq!(deserialize(&bytes, encoding), Some(Ok(respond.clone())));
+        assert!(deserialize::<Request>(&bytes, encoding).unwrap().is_err());
+    }
+    assert_eq!(
+        serialize(&Request::TopologicalSort { graph }, Encoding::Json),
+        Some(Ok(Bytes::from(
+            &br#"{"topological_sort":{"graph":[["a","b",1.5]]}}"#[..]
+        )))
+    );
+    assert_eq!(serialize(&request, Encoding::Native), None);
+}
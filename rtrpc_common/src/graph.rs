@@ -1,8 +1,12 @@
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
 use std::collections::{hash_map, HashMap};
 
 /// A directed graph represented with an adjacency list,
-/// which allowing self loop and parallel edges
-#[derive(Debug, PartialEq, Clone)]
+/// which allowing self loop and parallel edges.
+/// It is serialized as the list of edges, like the native encoding.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, String, f64)>", into = "Vec<(String, String, f64)>")]
 pub struct Graph {
     nodes: HashMap<String, Vec<(String, f64)>>,
 }
@@ -26,7 +30,7 @@ impl Graph {
         }
         Graph { nodes }
     }
-    pub fn nodes(&self) -> hash_map::Keys<String, Vec<(String, f64)>> {
+    pub fn nodes(&self) -> hash_map::Keys<'_, String, Vec<(String, f64)>> {
         self.nodes.keys()
     }
     pub fn edges(&self, node: &str) -> Option<&Vec<(String, f64)>> {
@@ -35,13 +39,50 @@ impl Graph {
     pub fn contains_node(&self, node: &str) -> bool {
         self.nodes.contains_key(node)
     }
+    pub fn edge_count(&self) -> usize {
+        self.nodes.values().map(Vec::len).sum()
+    }
+    /// Whether every weight of the graph is finite
+    pub fn is_finite(&self) -> bool {
+        self.nodes
+            .values()
+            .flatten()
+            .all(|(_, weight)| weight.is_finite())
+    }
+    /// The SHA-256 of the sorted nodes and edges, which is the same for the equal graphs
+    /// whatever the order of their edges and of the `HashMap`
+    pub fn content_hash(&self) -> [u8; 32] {
+        fn put_str(context: &mut Context, s: &str) {
+            context.update(&(s.len() as u64).to_le_bytes());
+            context.update(s.as_bytes());
+        }
+        let mut context = Context::new(&SHA256);
+        let mut nodes: Vec<&String> = self.nodes.keys().collect();
+        nodes.sort();
+        for node in nodes {
+            put_str(&mut context, node);
+            let mut edges: Vec<(&str, u64)> = self.nodes[node]
+                .iter()
+                .map(|(end, weight)| (end.as_str(), weight.to_bits()))
+                .collect();
+            edges.sort();
+            context.update(&(edges.len() as u64).to_le_bytes());
+            for (end, weight) in edges {
+                put_str(&mut context, end);
+                context.update(&weight.to_le_bytes());
+            }
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(context.finish().as_ref());
+        hash
+    }
 }
 
-impl Into<Vec<(String, String, f64)>> for Graph {
-    fn into(self) -> Vec<(String, String, f64)> {
+impl From<Graph> for Vec<(String, String, f64)> {
+    fn from(graph: Graph) -> Self {
         let mut vec: Vec<(String, String, f64)> = Vec::new();
-        for node in self.nodes() {
-            for (target, weight) in self.edges(node).unwrap() {
+        for node in graph.nodes() {
+            for (target, weight) in graph.edges(node).unwrap() {
                 vec.push((node.clone(), target.clone(), *weight))
             }
         }
@@ -82,6 +123,7 @@ where
 }
 
 #[test]
+#[allow(clippy::needless_borrow, clippy::into_iter_on_ref)]
 fn graph_construction() {
     use std::collections::HashSet;
     let graph = Graph::from_edges(&[
@@ -129,9 +171,191 @@ fn graph_construction() {
     assert!(graph.contains_node("e"));
     let vec: Vec<_> = graph.clone().into();
     assert_eq!(graph, Graph::from(vec));
+    let mut reversed: Vec<(String, String, f64)> = graph.clone().into();
+    reversed.reverse();
+    assert_eq!(graph.content_hash(), Graph::from(reversed).content_hash());
+    let other = Graph::from_edges(&[("a", "b", 1.0), ("b", "c", 2.0)]);
+    assert_ne!(graph.content_hash(), other.content_hash());
+    let renamed = Graph::from_edges(&[("a", "bb", 1.0), ("b", "c", 2.0)]);
+    assert_ne!(other.content_hash(), renamed.content_hash());
+}
+
+/// A directed graph whose edges have a capacity and a cost for each unit of flow,
+/// represented with an adjacency list like `Graph`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(
+    from = "Vec<(String, String, f64, f64)>",
+    into = "Vec<(String, String, f64, f64)>"
+)]
+pub struct FlowNetwork {
+    nodes: HashMap<String, Vec<(String, f64, f64)>>,
+}
+
+impl FlowNetwork {
+    pub fn from_edges<I>(iterable: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: IntoFlowEdge,
+    {
+        let mut nodes: HashMap<String, Vec<(String, f64, f64)>> = HashMap::new();
+        for edge in iterable.into_iter() {
+            let (start, end, capacity, cost) = edge.into_flow_edge();
+            if !nodes.contains_key(&start) {
+                nodes.insert(start.clone(), Vec::new());
+            }
+            if !nodes.contains_key(&end) {
+                nodes.insert(end.clone(), Vec::new());
+            }
+            nodes.get_mut(&start).unwrap().push((end, capacity, cost));
+        }
+        FlowNetwork { nodes }
+    }
+    pub fn nodes(&self) -> hash_map::Keys<'_, String, Vec<(String, f64, f64)>> {
+        self.nodes.keys()
+    }
+    pub fn edges(&self, node: &str) -> Option<&Vec<(String, f64, f64)>> {
+        self.nodes.get(node)
+    }
+    pub fn contains_node(&self, node: &str) -> bool {
+        self.nodes.contains_key(node)
+    }
+    pub fn edge_count(&self) -> usize {
+        self.nodes.values().map(Vec::len).sum()
+    }
+    /// Whether every capacity and cost of the network is finite
+    pub fn is_finite(&self) -> bool {
+        self.nodes
+            .values()
+            .flatten()
+            .all(|(_, capacity, cost)| capacity.is_finite() && cost.is_finite())
+    }
+}
+
+impl From<FlowNetwork> for Vec<(String, String, f64, f64)> {
+    fn from(network: FlowNetwork) -> Self {
+        let mut vec: Vec<(String, String, f64, f64)> = Vec::new();
+        for node in network.nodes() {
+            for (target, capacity, cost) in network.edges(node).unwrap() {
+                vec.push((node.clone(), target.clone(), *capacity, *cost))
+            }
+        }
+        vec
+    }
+}
+
+impl From<Vec<(String, String, f64, f64)>> for FlowNetwork {
+    fn from(vec: Vec<(String, String, f64, f64)>) -> Self {
+        Self::from_edges(vec)
+    }
+}
+
+pub trait IntoFlowEdge {
+    fn into_flow_edge(self) -> (String, String, f64, f64);
+}
+
+impl IntoFlowEdge for (String, String, f64, f64) {
+    fn into_flow_edge(self) -> (String, String, f64, f64) {
+        self
+    }
+}
+
+impl IntoFlowEdge for (&str, &str, f64, f64) {
+    fn into_flow_edge(self) -> (String, String, f64, f64) {
+        let (start, end, capacity, cost) = self;
+        (String::from(start), String::from(end), capacity, cost)
+    }
+}
+
+impl<T> IntoFlowEdge for &T
+where
+    T: IntoFlowEdge + Copy,
+{
+    fn into_flow_edge(self) -> (String, String, f64, f64) {
+        (*self).into_flow_edge()
+    }
+}
+
+#[test]
+fn flow_network_construction() {
+    let network = FlowNetwork::from_edges(&[
+        ("a", "b", 1.0, 2.0),
+        ("b", "c", 2.0, -1.0),
+        ("a", "b", 4.0, 3.0),
+    ]);
+    assert!(network.contains_node("a"));
+    assert!(network.contains_node("b"));
+    assert!(network.contains_node("c"));
+    assert_eq!(
+        network.edges("a"),
+        Some(&vec![
+            (String::from("b"), 1.0, 2.0),
+            (String::from("b"), 4.0, 3.0)
+        ])
+    );
+    assert_eq!(network.edges("c"), Some(&vec![]));
+    let vec: Vec<_> = network.clone().into();
+    assert_eq!(network, FlowNetwork::from(vec));
 }
 
 /// An error type indicate that a negative cycle
 /// can be reached from the start point
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NegativeCycle();
+
+/// An error type indicate that the graph contains a cycle,
+/// the nodes on the cycle are listed in the order of the edges
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Cycle(pub Vec<String>);
+
+/// An error type indicate that an edge has a weight which
+/// the algorithm can not accept, like a negative capacity
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct InvalidWeight(pub String, pub String, pub f64);
+
+/// The maximum flow from the source to the sink, with
+/// the flow on each edge and the partition of the minimum cut
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MaxFlow {
+    pub value: f64,
+    /// The flow on each edge as `(start, end, flow)`,
+    /// parallel edges are listed separately
+    pub flows: Vec<(String, String, f64)>,
+    /// The nodes on the source side of the minimum cut
+    pub source_side: Vec<String>,
+    /// The nodes on the sink side of the minimum cut
+    pub sink_side: Vec<String>,
+}
+
+/// The minimum spanning forest of a graph whose edges are treated as undirected
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpanningForest {
+    /// The chosen edges as `(start, end, weight)`
+    pub edges: Vec<(String, String, f64)>,
+    pub total_weight: f64,
+    /// The number of trees, which is 1 when the graph is connected
+    pub tree_count: u32,
+}
+
+/// The minimum cost flow from the source to the sink, with the flow on each edge
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MinCostFlow {
+    pub value: f64,
+    pub cost: f64,
+    /// The flow on each edge as `(start, end, flow)`,
+    /// parallel edges are listed separately
+    pub flows: Vec<(String, String, f64)>,
+}
+
+/// An error type indicate why the minimum cost flow can not be found
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MinCostFlowError {
+    /// An edge has a negative or non-finite capacity
+    InvalidCapacity(InvalidWeight),
+    /// An edge has a non-finite cost
+    InvalidCost(InvalidWeight),
+    /// A cycle with negative cost and positive capacity can be reached from the source
+    NegativeCycle,
+    /// The requested amount of flow is negative or NaN
+    InvalidAmount(f64),
+}
@@ -0,0 +1,461 @@
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// The id of the packets used in the handshake, which is "RTRP" in ASCII.
+/// The client must send a hello packet with this id before any request.
+pub const MAGIC: u32 = 0x5254_5250;
+
+/// The newest protocol version spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version spoken by this crate
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The payload encoding used by every version of the protocol
+pub const NATIVE_ENCODING: &str = "native";
+
+/// The payload encoding with varints for lengths, ids and integer weights
+pub const COMPACT_ENCODING: &str = "compact";
+
+/// The compact encoding which also sends the non-integer weights as f32,
+/// losing precision for the smaller frames
+pub const COMPACT_F32_ENCODING: &str = "compact-f32";
+
+/// The payloads are the JSON serialization of the requests and responds
+pub const JSON_ENCODING: &str = "json";
+
+/// The payloads are the MessagePack serialization of the requests and responds
+pub const MSGPACK_ENCODING: &str = "msgpack";
+
+/// The payloads are the CBOR serialization of the requests and responds
+pub const CBOR_ENCODING: &str = "cbor";
+
+/// The compression which leaves the payload unchanged
+pub const NO_COMPRESSION: &str = "none";
+
+/// The fast LZ4 block compression
+pub const LZ4_COMPRESSION: &str = "lz4";
+
+/// The zstd compression, slower than LZ4 with smaller frames
+pub const ZSTD_COMPRESSION: &str = "zstd";
+
+/// The frames carry no checksum
+pub const NO_CHECKSUM: &str = "none";
+
+/// Each frame ends with the CRC32C of the id and the payload
+pub const CRC32C_CHECKSUM: &str = "crc32c";
+
+/// The features supported by one side of the connection,
+/// the encodings, compressions and checksums are listed in the order of preference
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Features {
+    pub methods: Vec<String>,
+    pub encodings: Vec<String>,
+    pub compressions: Vec<String>,
+    pub checksums: Vec<String>,
+}
+
+impl Features {
+    /// All the features implemented by this crate
+    pub fn supported() -> Self {
+        Features {
+            methods: crate::request::METHODS
+                .iter()
+                .map(|&method| String::from(method))
+                .collect(),
+            encodings: vec![
+                String::from(NATIVE_ENCODING),
+                String::from(COMPACT_ENCODING),
+                String::from(COMPACT_F32_ENCODING),
+                String::from(JSON_ENCODING),
+                String::from(MSGPACK_ENCODING),
+                String::from(CBOR_ENCODING),
+            ],
+            compressions: vec![
+                String::from(NO_COMPRESSION),
+                String::from(LZ4_COMPRESSION),
+                String::from(ZSTD_COMPRESSION),
+            ],
+            checksums: vec![String::from(NO_CHECKSUM), String::from(CRC32C_CHECKSUM)],
+        }
+    }
+}
+
+/// The wire format of the packets after the handshake
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Encoding {
+    /// Big endian u32 for lengths and ids, f64 for weights
+    #[default]
+    Native,
+    /// LEB128 varints for lengths and ids, zigzag varints for integer weights,
+    /// and f32 for the other weights which fit when `f32_weights` is set
+    Compact { f32_weights: bool },
+    /// The serde formats, which are easier to speak from other languages.
+    /// The frames are like the native encoding.
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Find the encoding by the name used in the handshake
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            NATIVE_ENCODING => Some(Encoding::Native),
+            COMPACT_ENCODING => Some(Encoding::Compact { f32_weights: false }),
+            COMPACT_F32_ENCODING => Some(Encoding::Compact { f32_weights: true }),
+            JSON_ENCODING => Some(Encoding::Json),
+            MSGPACK_ENCODING => Some(Encoding::MessagePack),
+            CBOR_ENCODING => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+    /// The name used in the handshake
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Native => NATIVE_ENCODING,
+            Encoding::Compact { f32_weights: false } => COMPACT_ENCODING,
+            Encoding::Compact { f32_weights: true } => COMPACT_F32_ENCODING,
+            Encoding::Json => JSON_ENCODING,
+            Encoding::MessagePack => MSGPACK_ENCODING,
+            Encoding::Cbor => CBOR_ENCODING,
+        }
+    }
+}
+
+/// The compression of the payloads after the handshake
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Compression {
+    /// The frames carry no flags byte
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Find the compression by the name used in the handshake
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            NO_COMPRESSION => Some(Compression::None),
+            LZ4_COMPRESSION => Some(Compression::Lz4),
+            ZSTD_COMPRESSION => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+    /// The name used in the handshake
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => NO_COMPRESSION,
+            Compression::Lz4 => LZ4_COMPRESSION,
+            Compression::Zstd => ZSTD_COMPRESSION,
+        }
+    }
+}
+
+/// The integrity check of the frames after the handshake
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Checksum {
+    #[default]
+    None,
+    Crc32c,
+}
+
+impl Checksum {
+    /// Find the checksum by the name used in the handshake
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            NO_CHECKSUM => Some(Checksum::None),
+            CRC32C_CHECKSUM => Some(Checksum::Crc32c),
+            _ => None,
+        }
+    }
+    /// The name used in the handshake
+    pub fn name(self) -> &'static str {
+        match self {
+            Checksum::None => NO_CHECKSUM,
+            Checksum::Crc32c => CRC32C_CHECKSUM,
+        }
+    }
+}
+
+/// The proof of the identity of the client, sent in the hello
+#[derive(Debug, PartialEq, Clone)]
+pub enum Credentials {
+    /// A shared secret token given to the principal
+    Token(String),
+    /// The hex HMAC-SHA256 signature of the principal, the UNIX time in seconds and
+    /// a random hex nonce, made with the secret of the principal
+    Hmac {
+        principal: String,
+        timestamp: u64,
+        nonce: String,
+        signature: String,
+    },
+}
+
+impl Credentials {
+    /// Sign the principal, the timestamp and a new random nonce with the secret
+    pub fn hmac(principal: &str, secret: &[u8], timestamp: u64) -> Self {
+        let mut nonce = [0; 16];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("Can not generate a nonce");
+        let nonce = to_hex(&nonce);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let tag = hmac::sign(&key, hmac_message(principal, timestamp, &nonce).as_bytes());
+        Credentials::Hmac {
+            principal: String::from(principal),
+            timestamp,
+            nonce,
+            signature: to_hex(tag.as_ref()),
+        }
+    }
+    /// Check the signature of the HMAC credentials with the secret in constant time,
+    /// the timestamp and the reuse of the nonce are checked by the caller
+    pub fn verify_hmac(&self, secret: &[u8]) -> bool {
+        let (principal, timestamp, nonce, signature) = match self {
+            Credentials::Hmac {
+                principal,
+                timestamp,
+                nonce,
+                signature,
+            } => (principal, *timestamp, nonce, signature),
+            Credentials::Token(_) => return false,
+        };
+        let signature = match (from_hex(nonce), from_hex(signature)) {
+            (Some(_), Some(signature)) => signature,
+            _ => return false,
+        };
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let message = hmac_message(principal, timestamp, nonce);
+        hmac::verify(&key, message.as_bytes(), &signature).is_ok()
+    }
+}
+
+/// The signed message, the timestamp and the hex nonce have no colon
+/// so the principal can not be confused with them
+fn hmac_message(principal: &str, timestamp: u64, nonce: &str) -> String {
+    format!("RTRP:{}:{}:{}", principal, timestamp, nonce)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The first packet sent by the client, with the range of protocol versions it speaks
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: Features,
+    /// Sent only to the servers requiring authentication
+    pub credentials: Option<Credentials>,
+}
+
+impl Hello {
+    pub fn new(features: Features) -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features,
+            credentials: None,
+        }
+    }
+}
+
+/// The features chosen for the connection
+#[derive(Debug, PartialEq, Clone)]
+pub struct Agreement {
+    pub version: u32,
+    pub encoding: String,
+    pub compression: String,
+    pub checksum: String,
+    /// The methods supported by both sides
+    pub methods: Vec<String>,
+}
+
+/// The answer of the server to the hello
+#[derive(Debug, PartialEq, Clone)]
+pub enum Welcome {
+    Accept(Agreement),
+    /// The connection is refused, with a message telling the reason
+    Reject(String),
+}
+
+/// Choose the first item of the client's preference which the server also supports.
+fn choose(preferred: &[String], supported: &[String]) -> Option<String> {
+    preferred
+        .iter()
+        .find(|&item| supported.contains(item))
+        .cloned()
+}
+
+/// Decide the features used by the connection on the server side.
+/// When the client lists no methods, all the methods of the server are agreed.
+pub fn negotiate(hello: &Hello, supported: &Features) -> Welcome {
+    let version = hello.max_version.min(PROTOCOL_VERSION);
+    if version < hello.min_version.max(MIN_PROTOCOL_VERSION) {
+        return Welcome::Reject(format!(
+            "Unsupported protocol version {}-{}, the server speaks {}-{}.",
+            hello.min_version, hello.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    let encoding = match choose(&hello.features.encodings, &supported.encodings) {
+        Some(encoding) => encoding,
+        None => {
+            return Welcome::Reject(format!(
+                "No common encoding, the server supports {:?}.",
+                supported.encodings
+            ))
+        }
+    };
+    let compression = match choose(&hello.features.compressions, &supported.compressions) {
+        Some(compression) => compression,
+        None => {
+            return Welcome::Reject(format!(
+                "No common compression, the server supports {:?}.",
+                supported.compressions
+            ))
+        }
+    };
+    let checksum = match choose(&hello.features.checksums, &supported.checksums) {
+        Some(checksum) => checksum,
+        None => {
+            return Welcome::Reject(format!(
+                "No common checksum, the server supports {:?}.",
+                supported.checksums
+            ))
+        }
+    };
+    let methods = if hello.features.methods.is_empty() {
+        supported.methods.clone()
+    } else {
+        hello
+            .features
+            .methods
+            .iter()
+            .filter(|&method| supported.methods.contains(method))
+            .cloned()
+            .collect()
+    };
+    Welcome::Accept(Agreement {
+        version,
+        encoding,
+        compression,
+        checksum,
+        methods,
+    })
+}
+
+#[test]
+fn negotiate_test() {
+    let strings = |items: Vec<&str>| items.into_iter().map(String::from).collect::<Vec<_>>();
+    let server = Features {
+        methods: strings(vec!["shortest_path", "topological_sort"]),
+        encodings: strings(vec!["native", "other"]),
+        compressions: strings(vec!["none"]),
+        checksums: strings(vec!["none", "crc32c"]),
+    };
+    let client = Features {
+        methods: strings(vec!["shortest_path", "unknown"]),
+        encodings: strings(vec!["newer", "other", "native"]),
+        compressions: strings(vec!["none"]),
+        checksums: strings(vec!["none", "crc32c"]),
+    };
+    assert_eq!(
+        negotiate(&Hello::new(client.clone()), &server),
+        Welcome::Accept(Agreement {
+            version: PROTOCOL_VERSION,
+            encoding: String::from("other"),
+            compression: String::from("none"),
+            checksum: String::from("none"),
+            methods: strings(vec!["shortest_path"]),
+        })
+    );
+    let newer = Hello {
+        min_version: PROTOCOL_VERSION + 1,
+        max_version: PROTOCOL_VERSION + 2,
+        features: client.clone(),
+        credentials: None,
+    };
+    match negotiate(&newer, &server) {
+        Welcome::Reject(_) => {}
+        welcome => panic!("unexpected welcome {:?}", welcome),
+    }
+    let older = Hello {
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION + 2,
+        features: Features {
+            methods: vec![],
+            ..client.clone()
+        },
+        credentials: None,
+    };
+    match negotiate(&older, &server) {
+        Welcome::Accept(agreement) => {
+            assert_eq!(agreement.version, PROTOCOL_VERSION);
+            assert_eq!(agreement.methods, server.methods);
+        }
+        welcome => panic!("unexpected welcome {:?}", welcome),
+    }
+    for encoding in Features::supported().encodings {
+        assert_eq!(Encoding::from_name(&encoding).unwrap().name(), encoding);
+    }
+    for compression in Features::supported().compressions {
+        assert_eq!(Compression::from_name(&compression).unwrap().name(), compression);
+    }
+    for checksum in Features::supported().checksums {
+        assert_eq!(Checksum::from_name(&checksum).unwrap().name(), checksum);
+    }
+    let unknown_encoding = Features {
+        encodings: strings(vec!["newer"]),
+        ..client
+    };
+    match negotiate(&Hello::new(unknown_encoding), &server) {
+        Welcome::Reject(_) => {}
+        welcome => panic!("unexpected welcome {:?}", welcome),
+    }
+}
+
+#[test]
+fn credentials_test() {
+    let credentials = Credentials::hmac("tenant", b"secret", 1_700_000_000);
+    assert!(credentials.verify_hmac(b"secret"));
+    assert!(!credentials.verify_hmac(b"other"));
+    if let Credentials::Hmac {
+        nonce, signature, ..
+    } = &credentials
+    {
+        let replayed = Credentials::Hmac {
+            principal: String::from("tenant"),
+            timestamp: 1_700_000_001,
+            nonce: nonce.clone(),
+            signature: signature.clone(),
+        };
+        assert!(!replayed.verify_hmac(b"secret"));
+        let renonced = Credentials::Hmac {
+            principal: String::from("tenant"),
+            timestamp: 1_700_000_000,
+            nonce: String::from("00"),
+            signature: signature.clone(),
+        };
+        assert!(!renonced.verify_hmac(b"secret"));
+    }
+    // every signature has its own nonce
+    assert_ne!(
+        credentials,
+        Credentials::hmac("tenant", b"secret", 1_700_000_000)
+    );
+    assert!(!Credentials::Token(String::from("secret")).verify_hmac(b"secret"));
+}
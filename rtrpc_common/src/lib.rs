@@ -1,6 +1,18 @@
+mod compression;
 mod convert;
+mod format;
 mod graph;
+mod handshake;
+mod limits;
 mod packet;
+mod request;
+mod tls;
+mod transport;
 pub use convert::*;
 pub use graph::*;
+pub use handshake::*;
+pub use limits::*;
 pub use packet::*;
+pub use request::*;
+pub use tls::*;
+pub use transport::*;
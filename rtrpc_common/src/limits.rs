@@ -0,0 +1,31 @@
+use serde::Deserialize;
+
+/// The limits checked when decoding frames and payloads from the peer,
+/// so that a malformed packet is rejected before large memory is allocated
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The maximum length of the payload of a frame
+    pub max_frame_length: usize,
+    /// The maximum length of a string in bytes
+    pub max_string_length: usize,
+    /// The maximum number of items in a vector
+    pub max_vec_length: usize,
+    /// The maximum number of edges in a graph
+    pub max_graph_edges: usize,
+    /// The maximum `max_hops` of a shortest path request, each hop being
+    /// a round over every edge which keeps a predecessor table
+    pub max_hops: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frame_length: 8 * 1024 * 1024,
+            max_string_length: 64 * 1024,
+            max_vec_length: 1024 * 1024,
+            max_graph_edges: 1024 * 1024,
+            max_hops: 1024,
+        }
+    }
+}
@@ -1,4 +1,10 @@
-use bytes::{Bytes, BytesMut};
+use crate::compression::{compress, decompress};
+use crate::convert::{put_varint, try_get_varint, DecodeError};
+use crate::handshake::{Checksum, Compression, Encoding};
+use crate::limits::Limits;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::error::Error;
+use std::{fmt, io};
 use tokio::codec::*;
 
 /// The unit of information between client and server
@@ -20,13 +26,13 @@ impl From<BytesMut> for Packet {
     }
 }
 
-impl Into<Bytes> for Packet {
-    fn into(self) -> Bytes {
+impl From<Packet> for Bytes {
+    fn from(packet: Packet) -> Self {
         let mut buf = BytesMut::new();
         use bytes::BufMut;
-        buf.reserve(4 + self.payload.len());
-        buf.put_u32_be(self.id);
-        buf.extend_from_slice(&self.payload);
+        buf.reserve(4 + packet.payload.len());
+        buf.put_u32_be(packet.id);
+        buf.extend_from_slice(&packet.payload);
         buf.freeze()
     }
 }
@@ -43,17 +49,178 @@ fn packet_encode() {
     assert_eq!(packet, Packet::from(bytes))
 }
 
+/// The flag telling the payload of the frame is compressed
+const COMPRESSED: u8 = 1;
+
+/// The default size from which the payloads are compressed
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// The error of a frame whose checksum does not match, with the id read from it,
+/// which may be corrupted as well. The codec can still decode the following frames.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CorruptedFrame {
+    pub id: u32,
+}
+
+impl CorruptedFrame {
+    /// Find the corrupted frame carried by the error returned from the codec
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref().cloned()
+    }
+}
+
+impl fmt::Display for CorruptedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The checksum of frame {} does not match.", self.id)
+    }
+}
+
+impl Error for CorruptedFrame {}
+
 /// A codec that turns socket into Stream and Sink of Packet
 pub struct PacketCodec {
     inner: LengthDelimitedCodec,
+    max_frame_length: usize,
+    encoding: Encoding,
+    compression: Compression,
+    compression_threshold: usize,
+    checksum: Checksum,
 }
 impl PacketCodec {
     pub fn new() -> Self {
-        PacketCodec {
-            inner: length_delimited::Builder::new()
+        Self::with_limits(&Limits::default())
+    }
+    /// Create a codec which rejects the frames whose payload is longer than `limits.max_frame_length`
+    pub fn with_limits(limits: &Limits) -> Self {
+        let mut codec = PacketCodec {
+            inner: LengthDelimitedCodec::new(),
+            max_frame_length: limits.max_frame_length,
+            encoding: Encoding::Native,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            checksum: Checksum::None,
+        };
+        codec.rebuild();
+        codec
+    }
+    /// Switch to the encoding agreed in the handshake, which must happen between frames.
+    /// In the compact encoding the id is a varint and the length counts it.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+        self.rebuild();
+    }
+    /// The encoding currently used
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+    /// Switch to the compression agreed in the handshake, which must happen between frames.
+    /// Unless it is `Compression::None` every frame then has a flags byte after the id,
+    /// and the payloads of at least `threshold` bytes are compressed when it makes them smaller.
+    pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+        self.compression = compression;
+        self.compression_threshold = threshold;
+    }
+    /// Switch to the checksum agreed in the handshake, which must happen between frames.
+    /// With `Checksum::Crc32c` every frame ends with the CRC32C of the bytes after the length,
+    /// and a mismatch is reported as a `CorruptedFrame` error.
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = checksum;
+        self.rebuild();
+    }
+    /// Create the inner codec for the current encoding and checksum
+    fn rebuild(&mut self) {
+        let trailer = self.trailer_length();
+        let mut builder = length_delimited::Builder::new();
+        match self.encoding {
+            // a u32 varint takes at most 5 bytes
+            Encoding::Compact { .. } => {
+                builder.max_frame_length(self.max_frame_length + 5 + trailer)
+            }
+            _ => builder
                 .length_adjustment(4)
-                .new_codec(),
+                .max_frame_length(self.max_frame_length + trailer),
+        };
+        self.inner = builder.new_codec();
+    }
+    fn trailer_length(&self) -> usize {
+        match self.checksum {
+            Checksum::None => 0,
+            Checksum::Crc32c => 4,
+        }
+    }
+    /// Add the flags byte and compress the payload if needed
+    fn pack(&self, payload: Bytes) -> Bytes {
+        if self.compression == Compression::None {
+            return payload;
+        }
+        let compressed = if payload.len() >= self.compression_threshold {
+            compress(self.compression, &payload)
+        } else {
+            None
+        };
+        let mut buf = BytesMut::new();
+        match compressed {
+            Some(compressed) => {
+                buf.reserve(1 + compressed.len());
+                buf.put_u8(COMPRESSED);
+                buf.extend_from_slice(&compressed);
+            }
+            None => {
+                buf.reserve(1 + payload.len());
+                buf.put_u8(0);
+                buf.extend_from_slice(&payload);
+            }
         }
+        buf.freeze()
+    }
+    /// Remove the flags byte and decompress the payload if needed
+    fn unpack(&self, body: Bytes) -> Result<Bytes, io::Error> {
+        if self.compression == Compression::None {
+            return Ok(body);
+        }
+        match body.first() {
+            Some(0) => Ok(body.slice_from(1)),
+            Some(&COMPRESSED) => decompress(self.compression, &body[1..], self.max_frame_length),
+            Some(&flags) => Err(DecodeError::UnknownTag(flags).into()),
+            None => Err(DecodeError::Malformed.into()),
+        }
+    }
+    /// Read the id at the start of the frame, returns it with the rest of the frame
+    fn split_id(&self, frame: Bytes) -> Result<(u32, Bytes), io::Error> {
+        let mut cursor = io::Cursor::new(&*frame);
+        let id = match self.encoding {
+            Encoding::Compact { .. } => try_get_varint(&mut cursor, 32)? as u32,
+            _ if frame.len() < 4 => return Err(DecodeError::Malformed.into()),
+            _ => cursor.get_u32_be(),
+        };
+        let position = cursor.position() as usize;
+        Ok((id, frame.slice_from(position)))
+    }
+    /// Check and remove the checksum at the end of the frame
+    fn verify(&self, frame: Bytes) -> Result<Bytes, io::Error> {
+        if self.checksum == Checksum::None {
+            return Ok(frame);
+        }
+        if frame.len() < 4 {
+            return Err(DecodeError::Malformed.into());
+        }
+        let length = frame.len() - 4;
+        let expected = io::Cursor::new(&frame[length..]).get_u32_be();
+        let data = frame.slice_to(length);
+        if crc32c::crc32c(&data) != expected {
+            let id = self.split_id(data).map(|(id, _)| id).unwrap_or(0);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                CorruptedFrame { id },
+            ));
+        }
+        Ok(data)
+    }
+}
+
+impl Default for PacketCodec {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -61,8 +228,13 @@ impl Decoder for PacketCodec {
     type Item = Packet;
     type Error = <LengthDelimitedCodec as Decoder>::Error;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, Self::Error> {
-        let inner_result = self.inner.decode(src);
-        inner_result.map(|opt| opt.map(|frame| Packet::from(frame)))
+        let frame = match self.inner.decode(src)? {
+            Some(frame) => frame.freeze(),
+            None => return Ok(None),
+        };
+        let (id, body) = self.split_id(self.verify(frame)?)?;
+        let payload = self.unpack(body)?;
+        Ok(Some(Packet { id, payload }))
     }
 }
 
@@ -70,6 +242,106 @@ impl Encoder for PacketCodec {
     type Item = Packet;
     type Error = <LengthDelimitedCodec as Encoder>::Error;
     fn encode(&mut self, data: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.inner.encode(data.into(), dst)
+        let body = self.pack(data.payload);
+        let mut frame = BytesMut::with_capacity(5 + body.len() + self.trailer_length());
+        match self.encoding {
+            Encoding::Compact { .. } => put_varint(&mut frame, u64::from(data.id)),
+            _ => frame.put_u32_be(data.id),
+        }
+        frame.extend_from_slice(&body);
+        if self.checksum == Checksum::Crc32c {
+            let checksum = crc32c::crc32c(&frame);
+            frame.reserve(4);
+            frame.put_u32_be(checksum);
+        }
+        self.inner.encode(frame.freeze(), dst)
     }
 }
+
+#[test]
+fn packet_codec_limits() {
+    let limits = Limits {
+        max_frame_length: 8,
+        ..Limits::default()
+    };
+    let mut codec = PacketCodec::with_limits(&limits);
+    let mut bytes = BytesMut::new();
+    bytes.extend_from_slice(&[0, 0, 0, 4, 0xDE, 0xAD, 0xBE, 0xEF, 1, 2, 3, 4]);
+    assert_eq!(
+        codec.decode(&mut bytes).unwrap(),
+        Some(Packet {
+            id: 0xDEADBEEF,
+            payload: Bytes::from(&[1u8, 2, 3, 4][..]),
+        })
+    );
+    bytes.extend_from_slice(&[0, 0, 0, 9, 0xDE, 0xAD, 0xBE, 0xEF, 1, 2, 3, 4, 5]);
+    assert!(codec.decode(&mut bytes).is_err());
+    let mut codec = PacketCodec::new();
+    let mut bytes = BytesMut::new();
+    bytes.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFB, 0, 0]);
+    assert!(codec.decode(&mut bytes).is_err());
+}
+
+#[test]
+fn packet_codec_compact() {
+    let mut codec = PacketCodec::new();
+    codec.set_encoding(Encoding::Compact { f32_weights: false });
+    let packet = Packet {
+        id: 300,
+        payload: Bytes::from(&[1u8, 2][..]),
+    };
+    let mut bytes = BytesMut::new();
+    codec.encode(packet.clone(), &mut bytes).unwrap();
+    assert_eq!(&bytes[..], &[0, 0, 0, 4, 0xAC, 0x02, 1, 2]);
+    assert_eq!(codec.decode(&mut bytes).unwrap(), Some(packet));
+    bytes.extend_from_slice(&[0, 0, 0, 1, 0x80]);
+    assert!(codec.decode(&mut bytes).is_err());
+}
+
+#[test]
+fn packet_codec_compression() {
+    let mut codec = PacketCodec::new();
+    codec.set_compression(Compression::Lz4, 16);
+    let small = Packet {
+        id: 1,
+        payload: Bytes::from(&b"short"[..]),
+    };
+    let large = Packet {
+        id: 2,
+        payload: Bytes::from(b"node-name,".repeat(100)),
+    };
+    let mut bytes = BytesMut::new();
+    codec.encode(small.clone(), &mut bytes).unwrap();
+    assert_eq!(&bytes[..9], &[0, 0, 0, 6, 0, 0, 0, 1, 0]);
+    assert_eq!(codec.decode(&mut bytes).unwrap(), Some(small));
+    codec.encode(large.clone(), &mut bytes).unwrap();
+    assert_eq!(bytes[8], COMPRESSED);
+    assert!(bytes.len() < 200);
+    assert_eq!(codec.decode(&mut bytes).unwrap(), Some(large));
+    bytes.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 3, 0x80]);
+    assert!(codec.decode(&mut bytes).is_err());
+}
+
+#[test]
+fn packet_codec_checksum() {
+    let mut codec = PacketCodec::new();
+    codec.set_checksum(Checksum::Crc32c);
+    let packet = Packet {
+        id: 7,
+        payload: Bytes::from(&b"payload"[..]),
+    };
+    let mut bytes = BytesMut::new();
+    codec.encode(packet.clone(), &mut bytes).unwrap();
+    assert_eq!(bytes.len(), 4 + 4 + 7 + 4);
+    let mut corrupted = bytes.clone();
+    assert_eq!(codec.decode(&mut bytes).unwrap(), Some(packet.clone()));
+    corrupted[10] ^= 0x20;
+    codec.encode(packet.clone(), &mut corrupted).unwrap();
+    let err = codec.decode(&mut corrupted).unwrap_err();
+    assert_eq!(
+        CorruptedFrame::from_io_error(&err),
+        Some(CorruptedFrame { id: 7 })
+    );
+    // the frame after the corrupted one is still decoded
+    assert_eq!(codec.decode(&mut corrupted).unwrap(), Some(packet));
+}
@@ -0,0 +1,241 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// The request sent from the client to the server
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    /// Find the shortest path from start to end,
+    /// using at most `max_hops` edges when it is provided
+    ShortestPath {
+        graph: Graph,
+        start: String,
+        end: String,
+        max_hops: Option<u32>,
+    },
+    /// Find the strongly connected components of the graph
+    StronglyConnectedComponents { graph: Graph },
+    /// Sort the nodes of the graph in topological order
+    TopologicalSort { graph: Graph },
+    /// Find the nodes reachable from the node
+    Reachable { graph: Graph, node: String },
+    /// Find the nodes which can reach the node
+    CoReachable { graph: Graph, node: String },
+    /// Find the maximum flow and the minimum cut from source to sink,
+    /// using the edge weights as capacities
+    MaximumFlow {
+        graph: Graph,
+        source: String,
+        sink: String,
+    },
+    /// Find the minimum spanning forest, treating the edges as undirected
+    MinimumSpanningForest { graph: Graph },
+    /// Find the flow with minimum cost from source to sink, which is the
+    /// maximum flow when `amount` is not provided
+    MinimumCostFlow {
+        network: FlowNetwork,
+        source: String,
+        sink: String,
+        amount: Option<f64>,
+    },
+    /// Check the liveness and readiness of the server
+    Health {},
+    /// Describe the server, to check the compatibility before sending work
+    ServerInfo {},
+}
+
+/// The names of all the methods, in the order of the request variants
+pub const METHODS: [&str; 10] = [
+    "shortest_path",
+    "strongly_connected_components",
+    "topological_sort",
+    "reachable",
+    "co_reachable",
+    "maximum_flow",
+    "minimum_spanning_forest",
+    "minimum_cost_flow",
+    "health",
+    "server_info",
+];
+
+/// The methods answered by the server itself without a graph,
+/// which are always served and allowed
+pub const BUILTIN_METHODS: [&str; 2] = [METHODS[8], METHODS[9]];
+
+impl Request {
+    /// The name of the method, used in the handshake
+    pub fn method(&self) -> &'static str {
+        match self {
+            Request::ShortestPath { .. } => METHODS[0],
+            Request::StronglyConnectedComponents { .. } => METHODS[1],
+            Request::TopologicalSort { .. } => METHODS[2],
+            Request::Reachable { .. } => METHODS[3],
+            Request::CoReachable { .. } => METHODS[4],
+            Request::MaximumFlow { .. } => METHODS[5],
+            Request::MinimumSpanningForest { .. } => METHODS[6],
+            Request::MinimumCostFlow { .. } => METHODS[7],
+            Request::Health {} => METHODS[8],
+            Request::ServerInfo {} => METHODS[9],
+        }
+    }
+    /// The number of edges in the graph or the network of the request
+    pub fn graph_size(&self) -> usize {
+        match self {
+            Request::ShortestPath { graph, .. }
+            | Request::StronglyConnectedComponents { graph }
+            | Request::TopologicalSort { graph }
+            | Request::Reachable { graph, .. }
+            | Request::CoReachable { graph, .. }
+            | Request::MaximumFlow { graph, .. }
+            | Request::MinimumSpanningForest { graph } => graph.edge_count(),
+            Request::MinimumCostFlow { network, .. } => network.edge_count(),
+            Request::Health {} | Request::ServerInfo {} => 0,
+        }
+    }
+    /// The estimated work of the request, the edges times the nodes of the graph
+    /// like the Bellman-Ford algorithm, or the edges times the rounds of a hop limit
+    pub fn work(&self) -> u64 {
+        let rounds = match self {
+            Request::ShortestPath {
+                max_hops: Some(max_hops),
+                ..
+            } => *max_hops as usize,
+            Request::ShortestPath { graph, .. }
+            | Request::StronglyConnectedComponents { graph }
+            | Request::TopologicalSort { graph }
+            | Request::Reachable { graph, .. }
+            | Request::CoReachable { graph, .. }
+            | Request::MaximumFlow { graph, .. }
+            | Request::MinimumSpanningForest { graph } => graph.nodes().len(),
+            Request::MinimumCostFlow { network, .. } => network.nodes().len(),
+            Request::Health {} | Request::ServerInfo {} => 0,
+        };
+        self.graph_size() as u64 * rounds as u64
+    }
+    /// Whether every weight, capacity, cost and amount of the request is finite,
+    /// since JSON can not carry the others
+    pub fn is_finite(&self) -> bool {
+        match self {
+            Request::ShortestPath { graph, .. }
+            | Request::StronglyConnectedComponents { graph }
+            | Request::TopologicalSort { graph }
+            | Request::Reachable { graph, .. }
+            | Request::CoReachable { graph, .. }
+            | Request::MaximumFlow { graph, .. }
+            | Request::MinimumSpanningForest { graph } => graph.is_finite(),
+            Request::MinimumCostFlow {
+                network, amount, ..
+            } => network.is_finite() && amount.is_none_or(f64::is_finite),
+            Request::Health {} | Request::ServerInfo {} => true,
+        }
+    }
+}
+
+/// The respond sent from the server to the client,
+/// each variant answers the request variant with the same name
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Respond {
+    ShortestPath(Result<Option<Vec<String>>, NegativeCycle>),
+    StronglyConnectedComponents(Vec<Vec<String>>),
+    TopologicalSort(Result<Vec<String>, Cycle>),
+    Reachable(Option<Vec<String>>),
+    CoReachable(Option<Vec<String>>),
+    MaximumFlow(Result<Option<MaxFlow>, InvalidWeight>),
+    MinimumSpanningForest(Result<SpanningForest, InvalidWeight>),
+    MinimumCostFlow(Result<Option<MinCostFlow>, MinCostFlowError>),
+    Health(Health),
+    ServerInfo(ServerInfo),
+    /// The request is not handled by any method
+    Error(ServerError),
+}
+
+impl Respond {
+    /// Whether every number of the respond is finite, since JSON can not carry the others
+    pub fn is_finite(&self) -> bool {
+        let finite = |edges: &[(String, String, f64)]| edges.iter().all(|edge| edge.2.is_finite());
+        match self {
+            Respond::MaximumFlow(Ok(Some(flow))) => flow.value.is_finite() && finite(&flow.flows),
+            Respond::MinimumSpanningForest(Ok(forest)) => {
+                forest.total_weight.is_finite() && finite(&forest.edges)
+            }
+            Respond::MinimumCostFlow(Ok(Some(flow))) => {
+                flow.value.is_finite() && flow.cost.is_finite() && finite(&flow.flows)
+            }
+            Respond::MaximumFlow(Err(InvalidWeight(_, _, weight)))
+            | Respond::MinimumSpanningForest(Err(InvalidWeight(_, _, weight)))
+            | Respond::MinimumCostFlow(Err(MinCostFlowError::InvalidCapacity(InvalidWeight(
+                _,
+                _,
+                weight,
+            ))))
+            | Respond::MinimumCostFlow(Err(MinCostFlowError::InvalidCost(InvalidWeight(
+                _,
+                _,
+                weight,
+            ))))
+            | Respond::MinimumCostFlow(Err(MinCostFlowError::InvalidAmount(weight))) => {
+                weight.is_finite()
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The liveness and readiness of the server
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Health {
+    /// Always true when the server answers
+    pub live: bool,
+    /// False when the server is shutting down and no longer accepts connections
+    pub ready: bool,
+}
+
+/// The description of the server
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The version of the server
+    pub version: String,
+    /// The oldest protocol version the server can speak
+    pub min_protocol_version: u32,
+    /// The newest protocol version the server can speak
+    pub max_protocol_version: u32,
+    /// The seconds since the server started
+    pub uptime: u64,
+    /// The methods served, the agreement of a connection may have less of them
+    pub methods: Vec<String>,
+}
+
+/// The reasons for the server to answer a request with `Respond::Error`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerError {
+    /// The checksum of the request frame does not match, it may be sent again.
+    /// This is sent with the `MAGIC` id, as the id of the frame may be corrupted too.
+    Corrupted,
+    /// The principal of the connection is not allowed to make the request
+    Forbidden(String),
+    /// The server is shutting down and closes the connection, the requests which
+    /// were not answered may be sent again. This is sent with the `MAGIC` id.
+    GoingAway,
+    /// The client exceeds its quotas or the server is too busy, it may be sent again later
+    Overloaded(String),
+    /// The request can not be decoded or exceeds the limits of the server
+    Invalid(String),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Corrupted => write!(f, "The request frame is corrupted."),
+            ServerError::Forbidden(reason) => write!(f, "Forbidden: {}", reason),
+            ServerError::GoingAway => write!(f, "The server is shutting down."),
+            ServerError::Overloaded(reason) => write!(f, "Overloaded: {}", reason),
+            ServerError::Invalid(reason) => write!(f, "Invalid request: {}", reason),
+        }
+    }
+}
+
+impl Error for ServerError {}
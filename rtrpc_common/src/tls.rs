@@ -0,0 +1,155 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig,
+};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Load the certificate chain from the PEM file
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() => Err(invalid(path, "No certificate")),
+        Ok(certs) => Ok(certs),
+        Err(()) => Err(invalid(path, "Invalid certificate")),
+    }
+}
+
+/// Load the first PKCS#8 or RSA private key from the PEM file
+pub fn load_private_key(path: &Path) -> Result<PrivateKey, Error> {
+    let pem = fs::read(path)?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| invalid(path, "No private key"))
+}
+
+/// Load the certificates of the PEM file as trusted roots
+fn load_roots(path: &Path) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|err| invalid(path, &format!("Invalid root certificate ({:?})", err)))?;
+    }
+    Ok(roots)
+}
+
+/// Create the acceptor serving the certificate chain and key of the PEM files.
+/// When `client_ca` is provided, the clients must present a certificate signed by it.
+pub fn tls_acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<TlsAcceptor, Error> {
+    let mut config = match client_ca {
+        Some(path) => ServerConfig::new(AllowAnyAuthenticatedClient::new(load_roots(path)?)),
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config
+        .set_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Create the connector trusting the servers signed by the certificates of the PEM file.
+/// The `identity` is the certificate chain and key presented to the servers asking for it.
+pub fn tls_connector(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector, Error> {
+    let mut config = ClientConfig::new();
+    config.root_store = load_roots(ca)?;
+    if let Some((cert, key)) = identity {
+        config.set_single_client_cert(load_certs(cert)?, load_private_key(key)?);
+    }
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn invalid(path: &Path, message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} in {}.", message, path.display()),
+    )
+}
+
+#[test]
+fn tls_test() {
+    use futures::{Future, Stream};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_rustls::webpki::DNSNameRef;
+
+    let dir = std::env::temp_dir().join(format!("rtrpc-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut ca = CertificateParams::new(vec![]);
+    ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca).unwrap();
+    let write = |name: &str, cert: &Certificate| {
+        let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), pem).unwrap();
+        fs::write(
+            dir.join(format!("{}.key", name)),
+            cert.serialize_private_key_pem(),
+        )
+        .unwrap();
+    };
+    write("ca", &ca);
+    let server = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    write("server", &server);
+    let client = rcgen::generate_simple_self_signed(vec![String::from("client")]).unwrap();
+    write("client", &client);
+    let path = |name: &str| dir.join(name);
+
+    let acceptor = tls_acceptor(
+        &path("server.pem"),
+        &path("server.key"),
+        Some(&path("ca.pem")),
+    )
+    .unwrap();
+    let mut runtime = Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(
+        listener
+            .incoming()
+            .for_each(move |sock| {
+                let echo = acceptor
+                    .accept(sock)
+                    .and_then(|stream| tokio::io::read_exact(stream, [0u8; 4]))
+                    .and_then(|(stream, buf)| tokio::io::write_all(stream, buf))
+                    .then(|_| Ok(()));
+                tokio::spawn(echo);
+                Ok(())
+            })
+            .map_err(|_| ()),
+    );
+    let mut ping = |connector: TlsConnector, domain: &'static str| {
+        let ping = TcpStream::connect(&addr)
+            .and_then(move |sock| {
+                connector.connect(DNSNameRef::try_from_ascii_str(domain).unwrap(), sock)
+            })
+            .and_then(|stream| tokio::io::write_all(stream, *b"ping"))
+            .and_then(|(stream, _)| tokio::io::read_exact(stream, [0u8; 4]))
+            .map(|(_, buf)| buf);
+        runtime.block_on(ping)
+    };
+    let identity = Some((&*path("client.pem"), &*path("client.key")));
+    let connector = tls_connector(&path("ca.pem"), identity).unwrap();
+    assert_eq!(ping(connector.clone(), "localhost").unwrap(), *b"ping");
+    // the server name must match the certificate
+    assert!(ping(connector, "example.com").is_err());
+    // the client must present a certificate
+    let connector = tls_connector(&path("ca.pem"), None).unwrap();
+    assert!(ping(connector, "localhost").is_err());
+    // the server must be signed by the trusted certificate
+    let connector = tls_connector(&path("client.pem"), identity).unwrap();
+    assert!(ping(connector, "localhost").is_err());
+    assert!(load_private_key(&path("ca.pem")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,271 @@
+use crate::limits::Limits;
+use futures::{future, Async, Future, Poll};
+use serde::de::{self, Deserialize, Deserializer};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tungstenite::error::ProtocolError;
+use tungstenite::handshake::{HandshakeError, HandshakeRole, MidHandshake};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, WebSocket};
+
+/// A connection the packets can be sent over, which may be of any transport
+pub trait Transport: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> Transport for T {}
+
+/// The address of a server, which is a TCP address like `127.0.0.1:8080`
+/// or the path of a Unix domain socket like `unix:/run/rtrpc.sock`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// The prefix of the Unix domain socket paths
+const UNIX_PREFIX: &str = "unix:";
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Tcp(addr)
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => s.parse().map(Address::Tcp),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid address {}", s)))
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A stream carried by the binary messages of a WebSocket. The messages are joined
+/// into a byte stream, so they do not need to match the frames, and the written bytes
+/// are split into messages no longer than the maximum message size.
+pub struct WebSocketStream<S> {
+    socket: WebSocket<S>,
+    max_message_size: usize,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl<S> WebSocketStream<S> {
+    fn new(socket: WebSocket<S>, max_message_size: usize) -> Self {
+        WebSocketStream {
+            socket,
+            max_message_size,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            match self.socket.read() {
+                Ok(Message::Binary(data)) => {
+                    self.pending = data;
+                    self.position = 0;
+                }
+                Ok(Message::Text(_)) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Only binary messages are accepted.",
+                    ))
+                }
+                Ok(Message::Close(_)) => return Ok(0),
+                // the pings are answered by the socket itself
+                Ok(_) => {}
+                Err(err) => return to_io_result(err).map(|_| 0),
+            }
+        }
+        let length = buf.len().min(self.pending.len() - self.position);
+        buf[..length].copy_from_slice(&self.pending[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl<S: Read + Write> Write for WebSocketStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf = &buf[..buf.len().min(self.max_message_size)];
+        match self.socket.write(Message::Binary(buf.to_vec())) {
+            Ok(()) => Ok(buf.len()),
+            // the message is kept in the write buffer of the socket and sent by `flush`
+            Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => {
+                Ok(buf.len())
+            }
+            Err(err) => to_io_result(err).map(|_| 0),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush().or_else(to_io_result)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for WebSocketStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for WebSocketStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let result = match self.socket.close(None) {
+            Ok(()) => self.socket.flush(),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => {
+                Ok(Async::NotReady)
+            }
+            Err(err) => to_io_result(err).map(Async::Ready),
+        }
+    }
+}
+
+/// The size of the WebSocket messages, which fit a frame with the length, id, flags and checksum
+pub fn websocket_message_size(limits: &Limits) -> usize {
+    limits.max_frame_length + 14
+}
+
+/// Turn the error of the socket into the result of the io traits, a closed connection
+/// is the end of the stream even when the peer did not send the close message
+fn to_io_result(err: tungstenite::Error) -> io::Result<()> {
+    match err {
+        tungstenite::Error::ConnectionClosed
+        | tungstenite::Error::AlreadyClosed
+        | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => Ok(()),
+        tungstenite::Error::Io(err) => Err(err),
+        err => Err(io::Error::new(ErrorKind::InvalidData, err)),
+    }
+}
+
+fn config(max_message_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..WebSocketConfig::default()
+    }
+}
+
+/// Finish the handshake of the WebSocket, which is resumed whenever the stream is ready
+fn handshake<R, F>(start: F) -> impl Future<Item = R::FinalResult, Error = io::Error>
+where
+    R: HandshakeRole,
+    F: FnOnce() -> Result<R::FinalResult, HandshakeError<R>>,
+{
+    let mut start = Some(start);
+    let mut interrupted: Option<MidHandshake<R>> = None;
+    future::poll_fn(move || {
+        let result = match interrupted.take() {
+            Some(mid) => mid.handshake(),
+            None => (start.take().expect("Handshake polled after completion."))(),
+        };
+        match result {
+            Ok(done) => Ok(Async::Ready(done)),
+            Err(HandshakeError::Interrupted(mid)) => {
+                interrupted = Some(mid);
+                Ok(Async::NotReady)
+            }
+            Err(HandshakeError::Failure(err)) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("WebSocket handshake failed: {}", err),
+            )),
+        }
+    })
+}
+
+/// Accept the WebSocket upgrade on the stream, the messages longer than
+/// `max_message_size` are rejected and never sent
+// the errors of the handshake are defined by tungstenite
+#[allow(clippy::result_large_err)]
+pub fn accept_websocket<S>(
+    stream: S,
+    max_message_size: usize,
+) -> impl Future<Item = WebSocketStream<S>, Error = io::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let config = config(max_message_size);
+    handshake(move || tungstenite::accept_with_config(stream, Some(config)))
+        .map(move |socket| WebSocketStream::new(socket, max_message_size))
+}
+
+/// Upgrade the stream to a WebSocket with the url, the messages longer than
+/// `max_message_size` are rejected and never sent
+// the errors of the handshake are defined by tungstenite
+#[allow(clippy::result_large_err)]
+pub fn connect_websocket<S>(
+    stream: S,
+    url: String,
+    max_message_size: usize,
+) -> impl Future<Item = WebSocketStream<S>, Error = io::Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let config = config(max_message_size);
+    handshake(move || tungstenite::client::client_with_config(url, stream, Some(config)))
+        .map(move |(socket, _)| WebSocketStream::new(socket, max_message_size))
+}
+
+#[test]
+fn address_test() {
+    let tcp: Address = "127.0.0.1:8080".parse().unwrap();
+    assert_eq!(tcp, Address::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080))));
+    assert_eq!(tcp.to_string(), "127.0.0.1:8080");
+    let unix: Address = "unix:/run/rtrpc.sock".parse().unwrap();
+    assert_eq!(unix, Address::Unix(PathBuf::from("/run/rtrpc.sock")));
+    assert_eq!(unix.to_string(), "unix:/run/rtrpc.sock");
+    assert!("/run/rtrpc.sock".parse::<Address>().is_err());
+    let addrs: Vec<Address> = serde_json::from_str(r#"["127.0.0.1:8080", "unix:/run/rtrpc.sock"]"#)
+        .unwrap();
+    assert_eq!(addrs, vec![tcp, unix]);
+    assert!(serde_json::from_str::<Address>(r#""localhost""#).is_err());
+}
+
+#[test]
+fn websocket_stream_test() {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let mut stream = WebSocketStream::new(tungstenite::accept(sock).unwrap(), 4);
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    let sock = TcpStream::connect(addr).unwrap();
+    let url = format!("ws://{}/", addr);
+    let (socket, _) = tungstenite::client::client(url, sock).unwrap();
+    let mut stream = WebSocketStream::new(socket, 4);
+    // the bytes are split into messages of at most 4 bytes
+    assert_eq!(stream.write(b"0123456789").unwrap(), 4);
+    stream.write_all(b"456789").unwrap();
+    stream.flush().unwrap();
+    stream.socket.close(None).unwrap();
+    stream.socket.flush().unwrap();
+    assert_eq!(server.join().unwrap(), b"0123456789".to_vec());
+}
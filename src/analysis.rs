@@ -0,0 +1,269 @@
+use rtrpc_common::*;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+/// Collect the nodes of the graph in sorted order,
+/// so that the results does not depend on the order of the hash map.
+fn sorted_nodes(graph: &Graph) -> Vec<&str> {
+    let mut nodes: Vec<&str> = graph.nodes().map(String::as_str).collect();
+    nodes.sort();
+    nodes
+}
+
+/// Find the strongly connected components with the Tarjan's algorithm.
+/// The components are returned in reverse topological order of the condensation,
+/// and the nodes in each component are sorted.
+pub fn strongly_connected_components(graph: &Graph) -> Vec<Vec<String>> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    let mut lowlink: HashMap<&str, usize> = HashMap::new();
+    let mut on_stack: HashSet<&str> = HashSet::new();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut components: Vec<Vec<String>> = Vec::new();
+    for root in sorted_nodes(graph) {
+        if index.contains_key(root) {
+            continue;
+        }
+        // the explicit call stack, with the position of the next edge to visit
+        let mut frames: Vec<(&str, usize)> = Vec::new();
+        index.insert(root, index.len());
+        lowlink.insert(root, index[root]);
+        stack.push(root);
+        on_stack.insert(root);
+        frames.push((root, 0));
+        while let Some((node, position)) = frames.pop() {
+            let edges = graph.edges(node).unwrap();
+            if position < edges.len() {
+                frames.push((node, position + 1));
+                let target = edges[position].0.as_str();
+                if !index.contains_key(target) {
+                    index.insert(target, index.len());
+                    lowlink.insert(target, index[target]);
+                    stack.push(target);
+                    on_stack.insert(target);
+                    frames.push((target, 0));
+                } else if on_stack.contains(target) {
+                    let low = lowlink[node].min(index[target]);
+                    lowlink.insert(node, low);
+                }
+            } else {
+                if lowlink[node] == index[node] {
+                    let mut component: Vec<String> = Vec::new();
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack.remove(member);
+                        component.push(String::from(member));
+                        if member == node {
+                            break;
+                        }
+                    }
+                    component.sort();
+                    components.push(component);
+                }
+                if let Some(&(parent, _)) = frames.last() {
+                    let low = lowlink[parent].min(lowlink[node]);
+                    lowlink.insert(parent, low);
+                }
+            }
+        }
+    }
+    components
+}
+
+#[test]
+fn strongly_connected_components_test() {
+    let graph = Graph::from_edges(&[
+        ("a", "b", 1.0),
+        ("b", "c", 2.0),
+        ("a", "c", 4.0),
+        ("d", "c", 3.0),
+        ("c", "e", -4.0),
+        ("c", "e", 4.0),
+        ("f", "g", 4.0),
+        ("g", "f", -6.0),
+        ("f", "h", 2.0),
+        ("h", "f", 2.0),
+        ("i", "i", 1.0),
+    ]);
+    let make_components = |components: Vec<Vec<&str>>| {
+        components
+            .into_iter()
+            .map(|component| component.into_iter().map(String::from).collect())
+            .collect::<Vec<Vec<String>>>()
+    };
+    assert_eq!(
+        strongly_connected_components(&graph),
+        make_components(vec![
+            vec!["e"],
+            vec!["c"],
+            vec!["b"],
+            vec!["a"],
+            vec!["d"],
+            vec!["f", "g", "h"],
+            vec!["i"],
+        ])
+    );
+}
+
+/// Sort the nodes with the Kahn's algorithm, the smallest available node is picked first.
+/// Returns `Err(Cycle(nodes))` when the graph is not acyclic, and `nodes` is one of the cycles.
+pub fn topological_sort(graph: &Graph) -> Result<Vec<String>, Cycle> {
+    let mut in_degree: HashMap<&str, usize> = HashMap::new();
+    for node in graph.nodes() {
+        in_degree.entry(node).or_insert(0);
+        for (target, _) in graph.edges(node).unwrap() {
+            *in_degree.entry(target).or_insert(0) += 1;
+        }
+    }
+    let mut ready: BTreeSet<&str> = in_degree
+        .iter()
+        .filter(|(_, &degree)| degree == 0)
+        .map(|(&node, _)| node)
+        .collect();
+    let mut order: Vec<String> = Vec::new();
+    while let Some(&node) = ready.iter().next() {
+        ready.remove(node);
+        order.push(String::from(node));
+        for (target, _) in graph.edges(node).unwrap() {
+            let degree = in_degree.get_mut(target.as_str()).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.insert(target);
+            }
+        }
+    }
+    if order.len() == in_degree.len() {
+        return Ok(order);
+    }
+    // every remaining node has a remaining predecessor,
+    // so walking backward from any of them must run into a cycle
+    let mut predecessor: HashMap<&str, &str> = HashMap::new();
+    for node in sorted_nodes(graph) {
+        if in_degree[node] == 0 {
+            continue;
+        }
+        for (target, _) in graph.edges(node).unwrap() {
+            if in_degree[target.as_str()] > 0 {
+                predecessor.entry(target).or_insert(node);
+            }
+        }
+    }
+    let mut visited: Vec<&str> = Vec::new();
+    let mut current = *predecessor.keys().min().unwrap();
+    while !visited.contains(&current) {
+        visited.push(current);
+        current = predecessor[current];
+    }
+    let begin = visited.iter().position(|&node| node == current).unwrap();
+    let mut cycle: Vec<String> = visited[begin..]
+        .iter()
+        .map(|&node| String::from(node))
+        .collect();
+    cycle.reverse();
+    let smallest = (0..cycle.len()).min_by_key(|&i| &cycle[i]).unwrap();
+    cycle.rotate_left(smallest);
+    Err(Cycle(cycle))
+}
+
+#[test]
+fn topological_sort_test() {
+    let make_nodes =
+        |nodes: Vec<&str>| nodes.into_iter().map(String::from).collect::<Vec<String>>();
+    let graph = Graph::from_edges(&[
+        ("a", "b", 1.0),
+        ("b", "c", 2.0),
+        ("a", "c", 4.0),
+        ("d", "c", 3.0),
+        ("c", "e", -4.0),
+        ("c", "e", 4.0),
+    ]);
+    assert_eq!(
+        topological_sort(&graph),
+        Ok(make_nodes(vec!["a", "b", "d", "c", "e"]))
+    );
+    let graph = Graph::from_edges(&[
+        ("a", "b", 1.0),
+        ("b", "c", 1.0),
+        ("c", "d", 1.0),
+        ("d", "b", 1.0),
+        ("d", "e", 1.0),
+    ]);
+    assert_eq!(
+        topological_sort(&graph),
+        Err(Cycle(make_nodes(vec!["b", "c", "d"])))
+    );
+    let graph = Graph::from_edges(&[("a", "a", 1.0)]);
+    assert_eq!(topological_sort(&graph), Err(Cycle(make_nodes(vec!["a"]))));
+}
+
+/// Find the nodes which can be reached from the node by walking along the edges,
+/// or against the edges when `reverse` is true. The node itself is always included.
+/// Returns `None` when the node is not in the graph.
+fn search(graph: &Graph, node: &str, reverse: bool) -> Option<Vec<String>> {
+    if !graph.contains_node(node) {
+        return None;
+    }
+    let mut neighbors: HashMap<&str, Vec<&str>> = HashMap::new();
+    for source in graph.nodes() {
+        for (target, _) in graph.edges(source).unwrap() {
+            let (from, to) = if reverse {
+                (target.as_str(), source.as_str())
+            } else {
+                (source.as_str(), target.as_str())
+            };
+            neighbors.entry(from).or_default().push(to);
+        }
+    }
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut queue: VecDeque<&str> = VecDeque::new();
+    visited.insert(node);
+    queue.push_back(node);
+    while let Some(current) = queue.pop_front() {
+        for &next in neighbors.get(current).into_iter().flatten() {
+            if visited.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+    let mut nodes: Vec<String> = visited.into_iter().map(String::from).collect();
+    nodes.sort();
+    Some(nodes)
+}
+
+/// Find the sorted nodes reachable from the node, including itself.
+/// Returns `None` when the node is not in the graph.
+pub fn reachable(graph: &Graph, node: &str) -> Option<Vec<String>> {
+    search(graph, node, false)
+}
+
+/// Find the sorted nodes which can reach the node, including itself.
+/// Returns `None` when the node is not in the graph.
+pub fn co_reachable(graph: &Graph, node: &str) -> Option<Vec<String>> {
+    search(graph, node, true)
+}
+
+#[test]
+fn reachable_test() {
+    let graph = Graph::from_edges(&[
+        ("a", "b", 1.0),
+        ("b", "c", 2.0),
+        ("a", "c", 4.0),
+        ("d", "c", 3.0),
+        ("c", "e", -4.0),
+        ("c", "e", 4.0),
+        ("f", "g", 4.0),
+        ("g", "f", -6.0),
+    ]);
+    let make_nodes =
+        |nodes: Vec<&str>| Some(nodes.into_iter().map(String::from).collect::<Vec<String>>());
+    assert_eq!(reachable(&graph, "i"), None);
+    assert_eq!(co_reachable(&graph, "i"), None);
+    assert_eq!(reachable(&graph, "b"), make_nodes(vec!["b", "c", "e"]));
+    assert_eq!(
+        co_reachable(&graph, "c"),
+        make_nodes(vec!["a", "b", "c", "d"])
+    );
+    assert_eq!(reachable(&graph, "f"), make_nodes(vec!["f", "g"]));
+    assert_eq!(
+        co_reachable(&graph, "e"),
+        make_nodes(vec!["a", "b", "c", "d", "e"])
+    );
+}
@@ -0,0 +1,198 @@
+use ring::constant_time::verify_slices_are_equal;
+use rtrpc_common::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The longest time in seconds between the signature of HMAC credentials and the handshake
+pub const MAX_CLOCK_SKEW: u64 = 300;
+
+/// A client known to the server, with the credentials it can authenticate with
+/// and the requests it may make
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Principal {
+    pub name: String,
+    /// The shared secret token of the principal
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The secret used to sign the HMAC credentials of the principal
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+    /// The methods the principal may call, all of them when missing
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    /// The maximum number of edges in the graphs of the principal, below the server limit
+    #[serde(default)]
+    pub max_graph_edges: Option<usize>,
+}
+
+impl Principal {
+    /// Whether the principal may call the method, the built-in ones are always allowed
+    pub fn allows(&self, method: &str) -> bool {
+        if BUILTIN_METHODS.contains(&method) {
+            return true;
+        }
+        match &self.methods {
+            Some(methods) => methods.iter().any(|m| m == method),
+            None => true,
+        }
+    }
+    /// Check that the principal may make the request
+    pub fn authorize(&self, request: &Request) -> Result<(), ServerError> {
+        let method = request.method();
+        if !self.allows(method) {
+            return Err(ServerError::Forbidden(format!(
+                "{} may not call {}.",
+                self.name, method
+            )));
+        }
+        match self.max_graph_edges {
+            Some(limit) if request.graph_size() > limit => Err(ServerError::Forbidden(format!(
+                "The graph size of {} exceeds the limit {} of {}.",
+                request.graph_size(),
+                limit,
+                self.name
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The principals allowed to connect to the server, the clients must authenticate as one of them
+#[derive(Debug, Clone)]
+pub struct Policy {
+    principals: Vec<Principal>,
+    /// The nonces of the HMAC credentials accepted for each principal with their timestamp,
+    /// shared by the clones so that a credential is accepted once by the server
+    seen: Arc<Mutex<HashMap<String, HashMap<String, u64>>>>,
+}
+
+impl Policy {
+    pub fn new(principals: Vec<Principal>) -> Self {
+        Policy {
+            principals,
+            seen: Arc::default(),
+        }
+    }
+    /// Load the principals from the JSON array of the file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let principals = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        Ok(Policy::new(principals))
+    }
+    /// Whether some principals authenticate with a token, which is sent as is
+    pub fn has_tokens(&self) -> bool {
+        self.principals
+            .iter()
+            .any(|principal| principal.token.is_some())
+    }
+    /// Find the principal of the credentials, checking the HMAC timestamp against `now`
+    /// and refusing the HMAC credentials which were already accepted
+    pub fn authenticate(
+        &self,
+        credentials: Option<&Credentials>,
+        now: u64,
+    ) -> Result<&Principal, String> {
+        let principal = match credentials {
+            None => return Err(String::from("Authentication required.")),
+            Some(Credentials::Token(token)) => self.principals.iter().find(|principal| {
+                principal.token.as_ref().is_some_and(|expected| {
+                    verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok()
+                })
+            }),
+            Some(
+                credentials @ Credentials::Hmac {
+                    principal,
+                    timestamp,
+                    nonce,
+                    ..
+                },
+            ) => {
+                if now.abs_diff(*timestamp) > MAX_CLOCK_SKEW {
+                    return Err(String::from("The credentials are expired."));
+                }
+                let known = self.principals.iter().find(|known| {
+                    known.name == *principal
+                        && known
+                            .hmac_secret
+                            .as_ref()
+                            .is_some_and(|secret| credentials.verify_hmac(secret.as_bytes()))
+                });
+                if known.is_some() && !self.remember(principal, nonce, *timestamp, now) {
+                    return Err(String::from("The credentials were already used."));
+                }
+                known
+            }
+        };
+        principal.ok_or_else(|| String::from("Invalid credentials."))
+    }
+    /// Record the nonce of verified HMAC credentials, returns false when it was already used.
+    /// The nonces of the principal are forgotten once their credentials are expired.
+    fn remember(&self, principal: &str, nonce: &str, timestamp: u64, now: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let nonces = seen.entry(String::from(principal)).or_default();
+        nonces.retain(|_, timestamp| *timestamp + MAX_CLOCK_SKEW >= now);
+        nonces.insert(String::from(nonce), timestamp).is_none()
+    }
+}
+
+/// The current UNIX time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[test]
+fn policy_test() {
+    let policy: Vec<Principal> = serde_json::from_str(
+        r#"[
+            {"name": "router", "token": "router-token", "max_graph_edges": 2},
+            {"name": "batch", "hmac_secret": "batch-secret", "methods": ["topological_sort"]}
+        ]"#,
+    )
+    .unwrap();
+    let policy = Policy::new(policy);
+    let now = 1_700_000_000;
+    let token = |token: &str| Credentials::Token(String::from(token));
+    let router = policy
+        .authenticate(Some(&token("router-token")), now)
+        .unwrap();
+    assert_eq!(router.name, "router");
+    assert!(policy.authenticate(Some(&token("router")), now).is_err());
+    assert!(policy.authenticate(None, now).is_err());
+    let signed = Credentials::hmac("batch", b"batch-secret", now - 10);
+    let batch = policy.authenticate(Some(&signed), now).unwrap();
+    assert_eq!(batch.name, "batch");
+    // the same credentials are not accepted twice, by the clones of the policy too
+    assert!(policy.authenticate(Some(&signed), now + 1).is_err());
+    assert!(policy.clone().authenticate(Some(&signed), now).is_err());
+    let resigned = Credentials::hmac("batch", b"batch-secret", now - 10);
+    assert!(policy.authenticate(Some(&resigned), now).is_ok());
+    assert!(policy
+        .authenticate(Some(&signed), now + MAX_CLOCK_SKEW)
+        .is_err());
+    let forged = Credentials::hmac("batch", b"guess", now);
+    assert!(policy.authenticate(Some(&forged), now).is_err());
+    // the router has no HMAC secret
+    let signed = Credentials::hmac("router", b"", now);
+    assert!(policy.authenticate(Some(&signed), now).is_err());
+
+    let graph = Graph::from_edges(&[("a", "b", 1.0), ("b", "c", 1.0), ("c", "a", 1.0)]);
+    let request = Request::TopologicalSort { graph };
+    assert!(batch.authorize(&request).is_ok());
+    assert!(router.authorize(&request).is_err());
+    let request = Request::Reachable {
+        graph: Graph::from_edges(&[("a", "b", 1.0)]),
+        node: String::from("a"),
+    };
+    assert!(router.authorize(&request).is_ok());
+    assert!(batch.authorize(&request).is_err());
+    assert!(batch.authorize(&Request::Health {}).is_ok());
+}
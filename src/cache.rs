@@ -0,0 +1,284 @@
+use crate::core::{shortest_paths, ShortestPaths};
+use crate::metrics::Metrics;
+use rtrpc_common::*;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The bytes held by the cache by default
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The content hash of the graph and the start node
+type Key = ([u8; 32], String);
+
+/// The shortest paths from a start node, or the negative cycle reached from it
+type Paths = Arc<Result<ShortestPaths, NegativeCycle>>;
+
+/// The bytes held by an entry besides its start node and its paths: the key and the entry
+/// in the map, and the tick and the key in the recency order, the start being held by
+/// both keys. The spare room of the maps is not counted.
+const ENTRY_OVERHEAD: usize = 2 * size_of::<Key>() + size_of::<Entry>() + size_of::<u64>();
+
+struct Entry {
+    paths: Paths,
+    size: usize,
+    /// The tick of the last use, the key of the entry in the recency order
+    used: u64,
+}
+
+/// The least recently used entries, evicted when their size exceeds the capacity
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    size: usize,
+    /// The paths being computed, so the other threads missing them wait instead
+    pending: HashMap<Key, Arc<Pending>>,
+}
+
+/// The shortest paths computed by a thread for the other threads waiting for them
+#[derive(Default)]
+struct Pending {
+    /// Missing until they are computed, then `None` when the computation panicked
+    paths: Mutex<Option<Option<Paths>>>,
+    done: Condvar,
+}
+
+impl Pending {
+    fn wait(&self) -> Option<Paths> {
+        let mut paths = self.paths.lock().unwrap();
+        while paths.is_none() {
+            paths = self.done.wait(paths).unwrap();
+        }
+        paths.clone().unwrap()
+    }
+}
+
+/// Where the shortest paths of a lookup come from
+enum Lookup {
+    Hit(Paths),
+    /// Another thread is computing them
+    Wait(Arc<Pending>),
+    /// The thread computes them for the waiting ones
+    Compute(Arc<Pending>),
+}
+
+/// The shortest paths a thread computes, which are cached and given to the waiting
+/// threads when it is dropped, even when the computation panicked
+struct Computing<'a> {
+    cache: &'a Cache,
+    key: Key,
+    pending: Arc<Pending>,
+    paths: Option<Paths>,
+}
+
+impl Drop for Computing<'_> {
+    fn drop(&mut self) {
+        let mut lru = self.cache.lru.lock().unwrap();
+        lru.pending.remove(&self.key);
+        if let Some(paths) = &self.paths {
+            let size = 2 * self.key.1.len()
+                + ENTRY_OVERHEAD
+                + paths.as_ref().as_ref().map_or(0, |p| p.size());
+            if size <= self.cache.capacity {
+                let key = self.key.clone();
+                lru.insert(key, paths.clone(), size, self.cache.capacity);
+            }
+            self.cache.metrics.cache_lookup(false, lru.size);
+        }
+        *self.pending.paths.lock().unwrap() = Some(self.paths.take());
+        self.pending.done.notify_all();
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key) -> Option<Paths> {
+        let entry = self.entries.get_mut(key)?;
+        self.tick += 1;
+        self.order.remove(&entry.used);
+        self.order.insert(self.tick, key.clone());
+        entry.used = self.tick;
+        Some(entry.paths.clone())
+    }
+    fn insert(&mut self, key: Key, paths: Paths, size: usize, capacity: usize) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.order.remove(&entry.used);
+            self.size -= entry.size;
+        }
+        while self.size + size > capacity {
+            let (_, oldest) = match self.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.size -= self.entries.remove(&oldest).unwrap().size;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.size += size;
+        let used = self.tick;
+        self.entries.insert(key, Entry { paths, size, used });
+    }
+}
+
+/// The single-source shortest paths of the graphs sent again and again,
+/// keyed by the content hash of the graph and the start node,
+/// so the requests with another end are answered without running Bellman–Ford again
+pub struct Cache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    metrics: Arc<Metrics>,
+}
+
+impl Cache {
+    /// Create a cache holding about `capacity` bytes, which is disabled when it is 0
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            capacity,
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                size: 0,
+                pending: HashMap::new(),
+            }),
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+    /// Record the hits and the misses in the metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    /// Answer like `core::shortest_path` from the cached shortest paths of the start,
+    /// which are computed and cached when missing. The paths larger than the capacity
+    /// are not cached. The threads missing the paths another one is computing wait for
+    /// them, counted as hits.
+    pub fn shortest_path(
+        &self,
+        graph: &Graph,
+        start: &str,
+        end: &str,
+    ) -> Result<Option<Vec<String>>, NegativeCycle> {
+        if self.capacity == 0 {
+            return crate::core::shortest_path(graph, start, end);
+        }
+        if !graph.contains_node(start) || !graph.contains_node(end) {
+            return Ok(None);
+        }
+        let key = (graph.content_hash(), String::from(start));
+        let lookup = {
+            let mut lru = self.lru.lock().unwrap();
+            match (lru.get(&key), lru.pending.get(&key)) {
+                (Some(paths), _) => {
+                    self.metrics.cache_lookup(true, lru.size);
+                    Lookup::Hit(paths)
+                }
+                (None, Some(pending)) => Lookup::Wait(pending.clone()),
+                (None, None) => {
+                    let pending = Arc::new(Pending::default());
+                    lru.pending.insert(key.clone(), pending.clone());
+                    Lookup::Compute(pending)
+                }
+            }
+        };
+        // the start is in the graph, so the paths are found unless there is a cycle
+        let compute = || Arc::new(shortest_paths(graph, start).map(Option::unwrap));
+        let paths = match lookup {
+            Lookup::Hit(paths) => paths,
+            Lookup::Wait(pending) => match pending.wait() {
+                Some(paths) => {
+                    self.metrics
+                        .cache_lookup(true, self.lru.lock().unwrap().size);
+                    paths
+                }
+                None => compute(),
+            },
+            Lookup::Compute(pending) => {
+                let mut computing = Computing {
+                    cache: self,
+                    key,
+                    pending,
+                    paths: None,
+                };
+                let paths = compute();
+                computing.paths = Some(paths.clone());
+                paths
+            }
+        };
+        match paths.as_ref() {
+            Ok(paths) => Ok(paths.path(end)),
+            Err(cycle) => Err(cycle.clone()),
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+#[test]
+fn cache_test() {
+    let edges = [
+        ("a", "b", 1.0),
+        ("b", "c", 2.0),
+        ("a", "c", 4.0),
+        ("c", "e", -4.0),
+        ("f", "g", 4.0),
+        ("g", "f", -6.0),
+    ];
+    let graph = Graph::from_edges(&edges);
+    let reordered = Graph::from_edges(edges.iter().rev());
+    let metrics = Arc::new(Metrics::new());
+    let cache = Cache::new(DEFAULT_CACHE_SIZE).with_metrics(metrics.clone());
+    let path = |nodes: &[&str]| Ok(Some(nodes.iter().map(|&n| String::from(n)).collect()));
+    assert_eq!(
+        cache.shortest_path(&graph, "a", "e"),
+        path(&["a", "b", "c", "e"])
+    );
+    assert_eq!(cache.shortest_path(&reordered, "a", "b"), path(&["a", "b"]));
+    assert_eq!(cache.shortest_path(&graph, "a", "f"), Ok(None));
+    assert_eq!(cache.shortest_path(&graph, "a", "z"), Ok(None));
+    assert_eq!(cache.shortest_path(&graph, "f", "g"), Err(NegativeCycle {}));
+    assert_eq!(cache.shortest_path(&graph, "f", "a"), Err(NegativeCycle {}));
+    let text = metrics.encode();
+    assert!(text.contains("rtrpc_cache_hits_total 3"));
+    assert!(text.contains("rtrpc_cache_misses_total 2"));
+
+    // each entry of the start nodes a and b holds about 500 bytes
+    let small = Cache::new(1000);
+    let size = || small.lru.lock().unwrap().size;
+    small.shortest_path(&graph, "a", "e").unwrap();
+    let first = size();
+    assert!(first > 0 && first <= 1000);
+    small.shortest_path(&graph, "b", "e").unwrap();
+    let lru = small.lru.lock().unwrap();
+    assert_eq!(lru.entries.len(), 1);
+    assert!(lru
+        .entries
+        .contains_key(&(graph.content_hash(), String::from("b"))));
+    drop(lru);
+    // the threads missing the same paths at once compute them once
+    let metrics = Arc::new(Metrics::new());
+    let shared = Cache::new(DEFAULT_CACHE_SIZE).with_metrics(metrics.clone());
+    let barrier = std::sync::Barrier::new(8);
+    std::thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                barrier.wait();
+                assert_eq!(
+                    shared.shortest_path(&graph, "a", "c"),
+                    path(&["a", "b", "c"])
+                );
+            });
+        }
+    });
+    let text = metrics.encode();
+    assert!(text.contains("rtrpc_cache_hits_total 7"));
+    assert!(text.contains("rtrpc_cache_misses_total 1"));
+    assert!(shared.lru.lock().unwrap().pending.is_empty());
+    let tiny = Cache::new(10);
+    assert_eq!(tiny.shortest_path(&graph, "a", "b"), path(&["a", "b"]));
+    assert!(tiny.lru.lock().unwrap().entries.is_empty());
+}
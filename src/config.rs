@@ -0,0 +1,504 @@
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::server::{DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_IDLE_TIMEOUT, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::throttle::Quotas;
+use clap::builder::BoolishValueParser;
+use clap::{Args, Parser};
+use rtrpc_common::*;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// The command line of the server. Every option can also be given as its `RTRPC_*`
+/// environment variable, the options override the variables, which override the
+/// configuration file, which overrides the defaults.
+#[derive(Debug, Parser)]
+#[command(
+    name = "rtrpc",
+    about = "Serve the graph algorithms to the rtrpc clients"
+)]
+pub struct Cli {
+    /// The addresses to listen, `host:port` or `unix:<path>`, instead of the `listen` ones
+    #[arg(value_name = "ADDRESS", env = "RTRPC_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<Address>,
+    /// The TOML configuration file
+    #[arg(short, long, value_name = "FILE", env = "RTRPC_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// The addresses to accept the connections carried by WebSocket on
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        env = "RTRPC_WS_ADDR",
+        value_delimiter = ','
+    )]
+    pub websocket: Vec<Address>,
+    /// The address of the HTTP gateway
+    #[arg(long, value_name = "ADDRESS", env = "RTRPC_HTTP_ADDR")]
+    pub http: Option<SocketAddr>,
+    /// The local address of the Prometheus metrics endpoint
+    #[arg(long, value_name = "ADDRESS", env = "RTRPC_METRICS_ADDR")]
+    pub metrics: Option<SocketAddr>,
+    /// The number of threads computing the requests
+    #[arg(long, value_name = "COUNT", env = "RTRPC_WORKERS")]
+    pub workers: Option<usize>,
+    /// The most verbose level logged: off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", env = "RTRPC_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// The comma separated methods to serve
+    #[arg(
+        long,
+        value_name = "METHODS",
+        env = "RTRPC_METHODS",
+        value_delimiter = ','
+    )]
+    pub methods: Option<Vec<String>>,
+    /// The JSON file of the principals the clients must authenticate as
+    #[arg(long, value_name = "FILE", env = "RTRPC_AUTH_FILE")]
+    pub auth_file: Option<PathBuf>,
+    /// Replace the invalid UTF-8 sequences of the node names instead of rejecting them
+    #[arg(
+        long,
+        value_name = "BOOL",
+        env = "RTRPC_LOSSY_UTF8",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    pub lossy_utf8: Option<bool>,
+    /// The size in bytes from which the responds are compressed
+    #[arg(long, value_name = "BYTES", env = "RTRPC_COMPRESSION_THRESHOLD")]
+    pub compression_threshold: Option<usize>,
+    /// The approximate bytes of the shortest paths cache, 0 to disable it
+    #[arg(long, value_name = "BYTES", env = "RTRPC_CACHE_SIZE")]
+    pub cache_size: Option<usize>,
+    #[command(flatten)]
+    pub limits: LimitArgs,
+    #[command(flatten)]
+    pub quotas: QuotaArgs,
+    /// The seconds given to the new connections to send the hello packet
+    #[arg(long, value_name = "SECONDS", env = "RTRPC_HANDSHAKE_TIMEOUT")]
+    pub handshake_timeout: Option<u64>,
+    /// The seconds an accepted connection may stay without request
+    #[arg(long, value_name = "SECONDS", env = "RTRPC_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+    /// The seconds given to the connections to finish after the shutdown
+    #[arg(long, value_name = "SECONDS", env = "RTRPC_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+    /// The PEM file of the TLS certificate chain
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "RTRPC_TLS_CERT",
+        requires = "tls_key"
+    )]
+    pub tls_cert: Option<PathBuf>,
+    /// The PEM file of the TLS private key
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "RTRPC_TLS_KEY",
+        requires = "tls_cert"
+    )]
+    pub tls_key: Option<PathBuf>,
+    /// The PEM file of the certificates the clients must be signed by
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "RTRPC_TLS_CLIENT_CA",
+        requires = "tls_cert"
+    )]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Check the configuration and exit
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// The options of the `[limits]` of the configuration
+#[derive(Debug, Args)]
+pub struct LimitArgs {
+    /// The maximum length of the payload of a frame
+    #[arg(long, value_name = "BYTES", env = "RTRPC_MAX_FRAME_LENGTH")]
+    pub max_frame_length: Option<usize>,
+    /// The maximum length of a string in bytes
+    #[arg(long, value_name = "BYTES", env = "RTRPC_MAX_STRING_LENGTH")]
+    pub max_string_length: Option<usize>,
+    /// The maximum number of items in a vector
+    #[arg(long, value_name = "COUNT", env = "RTRPC_MAX_VEC_LENGTH")]
+    pub max_vec_length: Option<usize>,
+    /// The maximum number of edges in a graph
+    #[arg(long, value_name = "COUNT", env = "RTRPC_MAX_GRAPH_EDGES")]
+    pub max_graph_edges: Option<usize>,
+    /// The maximum `max_hops` of a shortest path request
+    #[arg(long, value_name = "COUNT", env = "RTRPC_MAX_HOPS")]
+    pub max_hops: Option<usize>,
+}
+
+/// The options of the `[quotas]` of the configuration
+#[derive(Debug, Args)]
+pub struct QuotaArgs {
+    /// The connections of an IP address at the same time
+    #[arg(long, value_name = "COUNT", env = "RTRPC_MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+    /// The requests of a connection computed at the same time
+    #[arg(long, value_name = "COUNT", env = "RTRPC_MAX_IN_FLIGHT")]
+    pub max_in_flight: Option<usize>,
+    /// The requests per second of a principal, or of an IP address without authentication
+    #[arg(long, value_name = "RATE", env = "RTRPC_REQUESTS_PER_SECOND")]
+    pub requests_per_second: Option<f64>,
+    /// The requests a client may send at once
+    #[arg(long, value_name = "COUNT", env = "RTRPC_BURST")]
+    pub burst: Option<f64>,
+    /// The total work of the requests computed at the same time
+    #[arg(long, value_name = "WORK", env = "RTRPC_MAX_WORK")]
+    pub max_work: Option<u64>,
+}
+
+/// The configuration of the server, read from a TOML file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The addresses of the protocol
+    pub listen: Vec<Address>,
+    /// The addresses of the protocol carried by WebSocket
+    pub websocket: Vec<Address>,
+    /// The address of the HTTP gateway, which is disabled when missing
+    pub http: Option<SocketAddr>,
+    /// The address of `GET /metrics`, which is disabled when missing
+    pub metrics: Option<SocketAddr>,
+    /// The number of threads computing the requests, the reactor thread when missing
+    pub workers: Option<usize>,
+    pub log_level: String,
+    /// The methods served, all of them by default
+    pub methods: Vec<String>,
+    pub auth_file: Option<PathBuf>,
+    pub lossy_utf8: bool,
+    pub compression_threshold: usize,
+    /// The approximate bytes of the shortest paths cache, which is disabled when 0
+    pub cache_size: usize,
+    pub limits: Limits,
+    /// The quotas of the clients, unlimited by default
+    pub quotas: Quotas,
+    pub timeouts: Timeouts,
+    pub tls: Option<TlsConfig>,
+}
+
+/// The timeouts in seconds
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// The time given to the new connections to send the hello packet
+    pub handshake: u64,
+    /// The time an accepted connection may stay without request while none is in flight
+    pub idle: u64,
+    /// The time given to the connections to finish after the shutdown
+    pub shutdown: u64,
+}
+
+/// The PEM files of the TLS certificate chain and private key,
+/// and of the certificates the clients must be signed by when provided
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: Vec::new(),
+            websocket: Vec::new(),
+            http: None,
+            metrics: None,
+            workers: None,
+            log_level: String::from("info"),
+            methods: METHODS.iter().map(|&method| String::from(method)).collect(),
+            auth_file: None,
+            lossy_utf8: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            cache_size: DEFAULT_CACHE_SIZE,
+            limits: Limits::default(),
+            quotas: Quotas::default(),
+            timeouts: Timeouts::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT.as_secs(),
+            idle: DEFAULT_IDLE_TIMEOUT.as_secs(),
+            shutdown: DEFAULT_SHUTDOWN_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration of the command line: the file, overridden by the options
+    /// and their environment variables, and check it
+    pub fn resolve(cli: Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+    /// Read the TOML file, the missing fields have their default value
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Can not read {}: {}", path.display(), err))?;
+        toml::from_str(&text).map_err(|err| format!("Invalid {}: {}", path.display(), err))
+    }
+    /// Override the configuration with the options of the command line,
+    /// or their environment variables when they are missing
+    pub fn apply_cli(&mut self, cli: Cli) {
+        fn set<T>(setting: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        if !cli.listen.is_empty() {
+            self.listen = cli.listen;
+        }
+        if !cli.websocket.is_empty() {
+            self.websocket = cli.websocket;
+        }
+        self.http = cli.http.or(self.http);
+        self.metrics = cli.metrics.or(self.metrics);
+        self.workers = cli.workers.or(self.workers);
+        set(&mut self.log_level, cli.log_level);
+        set(&mut self.methods, cli.methods);
+        self.auth_file = cli.auth_file.or(self.auth_file.take());
+        set(&mut self.lossy_utf8, cli.lossy_utf8);
+        set(&mut self.compression_threshold, cli.compression_threshold);
+        set(&mut self.cache_size, cli.cache_size);
+        let (limits, args) = (&mut self.limits, cli.limits);
+        set(&mut limits.max_frame_length, args.max_frame_length);
+        set(&mut limits.max_string_length, args.max_string_length);
+        set(&mut limits.max_vec_length, args.max_vec_length);
+        set(&mut limits.max_graph_edges, args.max_graph_edges);
+        set(&mut limits.max_hops, args.max_hops);
+        let (quotas, args) = (&mut self.quotas, cli.quotas);
+        quotas.max_connections_per_ip = args
+            .max_connections_per_ip
+            .or(quotas.max_connections_per_ip);
+        quotas.max_in_flight = args.max_in_flight.or(quotas.max_in_flight);
+        quotas.requests_per_second = args.requests_per_second.or(quotas.requests_per_second);
+        quotas.burst = args.burst.or(quotas.burst);
+        quotas.max_work = args.max_work.or(quotas.max_work);
+        set(&mut self.timeouts.handshake, cli.handshake_timeout);
+        set(&mut self.timeouts.idle, cli.idle_timeout);
+        set(&mut self.timeouts.shutdown, cli.shutdown_timeout);
+        // clap makes sure the certificate and the key are given together
+        if let (Some(cert), Some(key)) = (cli.tls_cert, cli.tls_key) {
+            self.tls = Some(TlsConfig {
+                cert,
+                key,
+                client_ca: cli.tls_client_ca,
+            });
+        }
+    }
+    /// Check that the configuration can be served
+    pub fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() {
+            return Err(String::from(
+                "No address to listen, provide one on the command line or in `listen`.",
+            ));
+        }
+        if self.workers == Some(0) {
+            return Err(String::from("`workers` must be at least 1."));
+        }
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return Err(format!(
+                "Invalid `log_level` {}, use off, error, warn, info, debug or trace.",
+                self.log_level
+            ));
+        }
+        if self.methods.is_empty() {
+            return Err(String::from("`methods` can not be empty."));
+        }
+        if let Some(method) = self
+            .methods
+            .iter()
+            .find(|method| !METHODS.contains(&method.as_str()))
+        {
+            return Err(format!("Unknown method {} in `methods`.", method));
+        }
+        let limits = [
+            ("max_frame_length", self.limits.max_frame_length),
+            ("max_string_length", self.limits.max_string_length),
+            ("max_vec_length", self.limits.max_vec_length),
+            ("max_graph_edges", self.limits.max_graph_edges),
+            ("max_hops", self.limits.max_hops),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, limit)| *limit == 0) {
+            return Err(format!("`limits.{}` must be positive.", name));
+        }
+        let quotas = [
+            ("max_connections_per_ip", self.quotas.max_connections_per_ip),
+            ("max_in_flight", self.quotas.max_in_flight),
+        ];
+        if let Some((name, _)) = quotas.iter().find(|(_, quota)| *quota == Some(0)) {
+            return Err(format!("`quotas.{}` must be positive.", name));
+        }
+        let rates = [
+            ("requests_per_second", self.quotas.requests_per_second),
+            ("burst", self.quotas.burst),
+        ];
+        let invalid = |rate: f64| !rate.is_finite() || rate <= 0.0;
+        if let Some((name, _)) = rates.iter().find(|(_, rate)| rate.is_some_and(invalid)) {
+            return Err(format!("`quotas.{}` must be a positive number.", name));
+        }
+        if self.quotas.max_work == Some(0) {
+            return Err(String::from("`quotas.max_work` must be positive."));
+        }
+        let timeouts = [
+            ("handshake", self.timeouts.handshake),
+            ("idle", self.timeouts.idle),
+        ];
+        if let Some((name, _)) = timeouts.iter().find(|(_, timeout)| *timeout == 0) {
+            return Err(format!("`timeouts.{}` must be positive.", name));
+        }
+        Ok(())
+    }
+    /// The most verbose level logged
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::INFO)
+    }
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.handshake)
+    }
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.idle)
+    }
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.shutdown)
+    }
+}
+
+#[test]
+fn config_test() {
+    let config: Config = toml::from_str(include_str!("../rtrpc.example.toml")).unwrap();
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(config.listen.len(), 2);
+    assert_eq!(config.tls.as_ref().unwrap().client_ca, None);
+    assert_eq!(config.quotas.max_in_flight, Some(8));
+
+    let config: Config = toml::from_str(
+        r#"
+        listen = ["127.0.0.1:8080"]
+        [limits]
+        max_graph_edges = 100
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.limits.max_graph_edges, 100);
+    assert_eq!(
+        config.limits.max_frame_length,
+        Limits::default().max_frame_length
+    );
+    assert_eq!(config.shutdown_timeout(), DEFAULT_SHUTDOWN_TIMEOUT);
+    assert_eq!(config.log_level(), LevelFilter::INFO);
+
+    let cli = parse(&[
+        "rtrpc",
+        "unix:/run/rtrpc.sock",
+        "--workers",
+        "4",
+        "--methods",
+        "reachable,co_reachable",
+    ])
+    .unwrap();
+    let mut overridden = config.clone();
+    overridden.apply_cli(cli);
+    assert_eq!(
+        overridden.listen,
+        vec!["unix:/run/rtrpc.sock".parse().unwrap()]
+    );
+    assert_eq!(overridden.workers, Some(4));
+    assert_eq!(overridden.methods, ["reachable", "co_reachable"]);
+    assert_eq!(overridden.limits.max_graph_edges, 100);
+    // the options override the environment variables, which override the file
+    let cli = parse_with_env(
+        &["rtrpc", "--workers", "4", "--max-work", "10"],
+        &[
+            ("RTRPC_WORKERS", "2"),
+            ("RTRPC_MAX_GRAPH_EDGES", "50"),
+            ("RTRPC_LOSSY_UTF8", "1"),
+        ],
+    )
+    .unwrap();
+    let mut overridden = config.clone();
+    overridden.apply_cli(cli);
+    assert_eq!(overridden.listen, config.listen);
+    assert_eq!(overridden.workers, Some(4));
+    assert_eq!(overridden.limits.max_graph_edges, 50);
+    assert!(overridden.lossy_utf8);
+    assert_eq!(overridden.quotas.max_work, Some(10));
+    assert!(parse(&["rtrpc", "--tls-cert", "server.pem"]).is_err());
+    assert!(parse(&["rtrpc", "localhost"]).is_err());
+    let cli = parse_with_env(&["rtrpc"], &[("RTRPC_IDLE_TIMEOUT", "60")]).unwrap();
+    let mut overridden = config.clone();
+    overridden.apply_cli(cli);
+    assert_eq!(overridden.idle_timeout(), Duration::from_secs(60));
+
+    assert!(toml::from_str::<Config>("listen = [\"localhost\"]").is_err());
+    assert!(toml::from_str::<Config>("port = 8080").is_err());
+    assert!(Config::default().validate().is_err());
+    let invalid = |edit: fn(&mut Config)| {
+        let mut config = config.clone();
+        edit(&mut config);
+        config.validate().is_err()
+    };
+    assert!(invalid(|config| config.workers = Some(0)));
+    assert!(invalid(|config| config.log_level = String::from("loud")));
+    assert!(invalid(|config| config.methods = vec![String::from("sort")]));
+    assert!(invalid(|config| config.methods.clear()));
+    assert!(invalid(|config| config.limits.max_vec_length = 0));
+    assert!(invalid(|config| config.quotas.max_in_flight = Some(0)));
+    assert!(invalid(
+        |config| config.quotas.requests_per_second = Some(0.0)
+    ));
+    assert!(invalid(|config| config.quotas.burst = Some(f64::NAN)));
+    assert!(invalid(|config| config.timeouts.handshake = 0));
+    assert!(invalid(|config| config.timeouts.idle = 0));
+}
+
+/// Parse the command line without the environment variables of the process
+#[cfg(test)]
+fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+    parse_with_env(args, &[])
+}
+
+/// Parse the command line with the environment variables given instead of the ones
+/// of the process, which clap can not be given, so the variables missing from the
+/// command line are appended to it as their option
+#[cfg(test)]
+fn parse_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<Cli, clap::Error> {
+    use clap::{CommandFactory, FromArgMatches};
+    let command = Cli::command();
+    let mut args: Vec<String> = args.iter().map(|&arg| String::from(arg)).collect();
+    for arg in command.get_arguments() {
+        let name = arg.get_env().and_then(|name| name.to_str());
+        let value = env.iter().find(|(env, _)| Some(*env) == name);
+        let long = arg.get_long().map(|long| format!("--{}", long));
+        if let (Some((_, value)), Some(long)) = (value, long) {
+            if !args.contains(&long) {
+                args.push(format!("{}={}", long, value));
+            }
+        }
+    }
+    let matches = command
+        .mut_args(|arg| arg.env(None))
+        .try_get_matches_from(args)?;
+    Cli::from_arg_matches(&matches)
+}
@@ -0,0 +1,19 @@
+use crate::cache::Cache;
+use crate::metrics::Metrics;
+use crate::status::Status;
+use crate::throttle::Throttle;
+use std::sync::Arc;
+
+/// What the requests are processed with besides their connection,
+/// shared by the connections, the workers and the gateway
+#[derive(Clone, Default)]
+pub struct Context {
+    /// Records the requests, the errors and the connections
+    pub metrics: Arc<Metrics>,
+    /// Answers the built-in methods, not ready after the shutdown
+    pub status: Arc<Status>,
+    /// Enforces the quotas, the refused requests are answered as overloaded
+    pub throttle: Arc<Throttle>,
+    /// Answers the shortest paths without hop limit
+    pub cache: Arc<Cache>,
+}
@@ -1,72 +1,133 @@
+use crate::analysis::*;
+use crate::auth::Principal;
+use crate::cache::Cache;
+use crate::context::Context;
+use crate::flow::*;
+use crate::spanning::*;
+use crate::status::Status;
 use rtrpc_common::*;
 use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+use tracing::{field, info, info_span, trace, warn};
 
-/// Calculate the shortest path from start to end with the Bellman–Ford algorithm.
+/// Run the Bellman–Ford algorithm from start on the nodes numbered from 0 to `node_count - 1`,
+/// where each arc is `(start, end, weight)`.
 /// Returns `Err(NegativeCycle)` when a negative cycle can be reached from the start.
-/// Returns `Ok(None)` when there are no path from start to end.
-/// This value is also returned when one of start and end is not in the graph.
-/// Returns `Ok(Some(path))` when the shortest path exist and `path` is the vector of nodes on path.
-pub fn shortest_path(
-    graph: &Graph,
-    start: &str,
-    end: &str,
-) -> Result<Option<Vec<String>>, NegativeCycle> {
-    if !graph.contains_node(start) {
-        return Ok(None);
-    }
-    if !graph.contains_node(end) {
-        return Ok(None);
-    }
-    let mut predecessor: HashMap<&str, Option<&str>> = HashMap::new();
-    let mut distance: HashMap<&str, f64> = HashMap::new();
-    for node in graph.nodes() {
-        predecessor.insert(node, None);
-        use std::f64;
-        distance.insert(node, f64::INFINITY);
-    }
-    distance.insert(start, 0.0);
-    let node_count = predecessor.len();
+/// Returns `Ok((distance, predecessor))` otherwise, where `predecessor` is the index of
+/// the last arc on the shortest path to each node, or `None` for the start and unreachable nodes.
+pub fn bellman_ford(
+    node_count: usize,
+    arcs: &[(usize, usize, f64)],
+    start: usize,
+) -> Result<(Vec<f64>, Vec<Option<usize>>), NegativeCycle> {
+    use std::f64;
+    let mut distance: Vec<f64> = vec![f64::INFINITY; node_count];
+    let mut predecessor: Vec<Option<usize>> = vec![None; node_count];
+    distance[start] = 0.0;
     for _ in 1..node_count {
         let mut updated = false;
-        for node in graph.nodes() {
-            for (target, weight) in graph.edges(node).unwrap() {
-                if distance.get(target.as_str()).unwrap()
-                    > &(distance.get(node.as_str()).unwrap() + weight)
-                {
-                    distance.insert(target, distance.get(node.as_str()).unwrap() + weight);
-                    predecessor.insert(target, Some(node));
-                    updated = true;
-                }
+        for (arc, &(source, target, weight)) in arcs.iter().enumerate() {
+            if distance[target] > distance[source] + weight {
+                distance[target] = distance[source] + weight;
+                predecessor[target] = Some(arc);
+                updated = true;
             }
         }
         if !updated {
             break;
         }
     }
+    for &(source, target, weight) in arcs {
+        if distance[target] > distance[source] + weight {
+            return Err(NegativeCycle {});
+        }
+    }
+    Ok((distance, predecessor))
+}
+
+/// The shortest paths from a start node to every node of a graph,
+/// which answer the shortest path to any end without running the algorithm again
+#[derive(Debug, PartialEq)]
+pub struct ShortestPaths {
+    index: HashMap<String, usize>,
+    names: Vec<String>,
+    /// The node before each node on its shortest path,
+    /// or `None` for the start and unreachable nodes
+    predecessor: Vec<Option<usize>>,
+    start: usize,
+}
+
+impl ShortestPaths {
+    /// The nodes on the shortest path from the start to end,
+    /// or `None` when there are no path or end is not in the graph
+    pub fn path(&self, end: &str) -> Option<Vec<String>> {
+        let mut current = *self.index.get(end)?;
+        let mut path: VecDeque<String> = VecDeque::new();
+        while current != self.start {
+            path.push_front(self.names[current].clone());
+            current = self.predecessor[current]?;
+        }
+        path.push_front(self.names[current].clone());
+        Some(path.into_iter().collect())
+    }
+    /// The approximate bytes held, counting the node names twice and the table overheads
+    pub fn size(&self) -> usize {
+        let names: usize = self.names.iter().map(String::len).sum();
+        2 * names + 64 * self.names.len()
+    }
+}
+
+/// Calculate the shortest paths from start to every node with the Bellman–Ford algorithm.
+/// Returns `Err(NegativeCycle)` when a negative cycle can be reached from the start.
+/// Returns `Ok(None)` when start is not in the graph.
+pub fn shortest_paths(graph: &Graph, start: &str) -> Result<Option<ShortestPaths>, NegativeCycle> {
+    if !graph.contains_node(start) {
+        return Ok(None);
+    }
+    let names: Vec<String> = graph.nodes().cloned().collect();
+    let index: HashMap<String, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.clone(), i))
+        .collect();
+    let mut arcs: Vec<(usize, usize, f64)> = Vec::new();
     for node in graph.nodes() {
         for (target, weight) in graph.edges(node).unwrap() {
-            if distance.get(target.as_str()).unwrap()
-                > &(distance.get(node.as_str()).unwrap() + weight)
-            {
-                return Err(NegativeCycle {});
-            }
+            arcs.push((index[node], index[target], *weight));
         }
     }
-    let mut path: VecDeque<String> = VecDeque::new();
-    let mut current = end;
-    while current != start {
-        if let Some(node) = predecessor.get(current).unwrap() {
-            path.push_front(String::from(current));
-            current = node
-        } else {
-            return Ok(None);
-        }
+    let start = index[start];
+    let (_, predecessor) = bellman_ford(names.len(), &arcs, start)?;
+    let predecessor = predecessor
+        .into_iter()
+        .map(|arc| arc.map(|arc| arcs[arc].0))
+        .collect();
+    Ok(Some(ShortestPaths {
+        index,
+        names,
+        predecessor,
+        start,
+    }))
+}
+
+/// Calculate the shortest path from start to end with the Bellman–Ford algorithm.
+/// Returns `Err(NegativeCycle)` when a negative cycle can be reached from the start.
+/// Returns `Ok(None)` when there are no path from start to end.
+/// This value is also returned when one of start and end is not in the graph.
+/// Returns `Ok(Some(path))` when the shortest path exist and `path` is the vector of nodes on path.
+pub fn shortest_path(
+    graph: &Graph,
+    start: &str,
+    end: &str,
+) -> Result<Option<Vec<String>>, NegativeCycle> {
+    if !graph.contains_node(end) {
+        return Ok(None);
     }
-    path.push_front(String::from(current));
-    Ok(Some(path.into_iter().collect()))
+    Ok(shortest_paths(graph, start)?.and_then(|paths| paths.path(end)))
 }
 
 #[test]
+#[allow(clippy::redundant_closure)]
 fn shortest_path_test() {
     let graph = Graph::from_edges(&[
         ("a", "b", 1.0),
@@ -98,15 +159,405 @@ fn shortest_path_test() {
     );
 }
 
+/// Calculate the shortest path from start to end which use at most `max_hops` edges.
+/// The k-th round of the Bellman–Ford algorithm only relaxes the distances found in
+/// the previous round, so the result is well-defined even with reachable negative cycles,
+/// and the path may visit the same node more than once in that case.
+/// The rounds stop early once nothing changes, but go on until `max_hops` around
+/// a reachable negative cycle, so the hop limit of the requests is bounded.
+/// Returns `None` when there are no such path from start to end.
+/// This value is also returned when one of start and end is not in the graph.
+/// Returns `Some(path)` otherwise and `path` is the vector of nodes on path.
+pub fn shortest_path_within(
+    graph: &Graph,
+    start: &str,
+    end: &str,
+    max_hops: u32,
+) -> Option<Vec<String>> {
+    if !graph.contains_node(start) {
+        return None;
+    }
+    if !graph.contains_node(end) {
+        return None;
+    }
+    use std::f64;
+    let names: Vec<&str> = graph.nodes().map(String::as_str).collect();
+    let index: HashMap<&str, u32> = names
+        .iter()
+        .enumerate()
+        .map(|(i, &name)| (name, i as u32))
+        .collect();
+    let mut arcs: Vec<(u32, u32, f64)> = Vec::new();
+    for node in graph.nodes() {
+        for (target, weight) in graph.edges(node).unwrap() {
+            arcs.push((index[node.as_str()], index[target.as_str()], *weight));
+        }
+    }
+    let mut distance: Vec<f64> = vec![f64::INFINITY; names.len()];
+    distance[index[start] as usize] = 0.0;
+    let mut next_distance = distance.clone();
+    // the nodes updated in each round with their predecessor, sorted by node,
+    // used to rebuild the path without keeping a table of every node for every round
+    let mut rounds: Vec<Vec<(u32, u32)>> = Vec::new();
+    // the position of each node in the updates of the current round
+    let mut position: Vec<Option<u32>> = vec![None; names.len()];
+    for _ in 0..max_hops {
+        next_distance.copy_from_slice(&distance);
+        let mut updates: Vec<(u32, u32)> = Vec::new();
+        for &(source, target, weight) in &arcs {
+            let candidate = distance[source as usize] + weight;
+            if next_distance[target as usize] > candidate {
+                next_distance[target as usize] = candidate;
+                match position[target as usize] {
+                    Some(i) => updates[i as usize].1 = source,
+                    None => {
+                        position[target as usize] = Some(updates.len() as u32);
+                        updates.push((target, source));
+                    }
+                }
+            }
+        }
+        if updates.is_empty() {
+            break;
+        }
+        for &(node, _) in &updates {
+            position[node as usize] = None;
+        }
+        updates.sort_unstable();
+        std::mem::swap(&mut distance, &mut next_distance);
+        rounds.push(updates);
+    }
+    let mut current = index[end];
+    if distance[current as usize].is_infinite() {
+        return None;
+    }
+    let mut path: VecDeque<String> = VecDeque::new();
+    let mut round = rounds.len();
+    loop {
+        path.push_front(String::from(names[current as usize]));
+        // find the last round which updated the distance of current node
+        let last = rounds[..round]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(r, updates)| {
+                let i = updates.binary_search_by_key(&current, |&(node, _)| node);
+                i.ok().map(|i| (r, updates[i].1))
+            });
+        match last {
+            Some((r, predecessor)) => {
+                current = predecessor;
+                round = r;
+            }
+            None => break,
+        }
+    }
+    Some(path.into_iter().collect())
+}
+
+#[test]
+fn shortest_path_within_test() {
+    let graph = Graph::from_edges(&[
+        ("a", "b", 1.0),
+        ("b", "c", 2.0),
+        ("a", "c", 4.0),
+        ("d", "c", 3.0),
+        ("c", "e", -4.0),
+        ("c", "e", 4.0),
+        ("f", "g", 4.0),
+        ("g", "f", -6.0),
+        ("f", "h", 2.0),
+        ("h", "f", 2.0),
+    ]);
+    let make_path =
+        |path: Vec<&str>| Some(path.into_iter().map(String::from).collect::<Vec<String>>());
+    assert_eq!(shortest_path_within(&graph, "i", "a", 3), None);
+    assert_eq!(shortest_path_within(&graph, "a", "i", 3), None);
+    assert_eq!(shortest_path_within(&graph, "a", "h", 3), None);
+    assert_eq!(
+        shortest_path_within(&graph, "a", "a", 0),
+        make_path(vec!["a"])
+    );
+    assert_eq!(shortest_path_within(&graph, "a", "b", 0), None);
+    assert_eq!(
+        shortest_path_within(&graph, "a", "e", 2),
+        make_path(vec!["a", "c", "e"])
+    );
+    assert_eq!(
+        shortest_path_within(&graph, "a", "e", 3),
+        make_path(vec!["a", "b", "c", "e"])
+    );
+    assert_eq!(
+        shortest_path_within(&graph, "f", "h", 1),
+        make_path(vec!["f", "h"])
+    );
+    assert_eq!(
+        shortest_path_within(&graph, "f", "h", 3),
+        make_path(vec!["f", "g", "f", "h"])
+    );
+    assert_eq!(
+        shortest_path_within(&graph, "f", "f", 4),
+        make_path(vec!["f", "g", "f", "g", "f"])
+    );
+    // the negative cycle is followed for every allowed hop
+    let max_hops = Limits::default().max_hops;
+    let path = shortest_path_within(&graph, "f", "h", max_hops as u32).unwrap();
+    assert_eq!(path.len(), max_hops);
+    assert_eq!(path[max_hops - 2..], [String::from("f"), String::from("h")]);
+}
+
+/// Answer the request with the corresponding algorithm,
+/// or with the status of the server for the built-in methods.
+/// The shortest paths without hop limit are answered through the cache.
+pub fn process_request(request: Request, status: &Status, cache: &Cache) -> Respond {
+    match request {
+        Request::ShortestPath {
+            graph,
+            start,
+            end,
+            max_hops: None,
+        } => Respond::ShortestPath(cache.shortest_path(&graph, start.as_str(), end.as_str())),
+        Request::ShortestPath {
+            graph,
+            start,
+            end,
+            max_hops: Some(max_hops),
+        } => Respond::ShortestPath(Ok(shortest_path_within(
+            &graph,
+            start.as_str(),
+            end.as_str(),
+            max_hops,
+        ))),
+        Request::StronglyConnectedComponents { graph } => {
+            Respond::StronglyConnectedComponents(strongly_connected_components(&graph))
+        }
+        Request::TopologicalSort { graph } => Respond::TopologicalSort(topological_sort(&graph)),
+        Request::Reachable { graph, node } => Respond::Reachable(reachable(&graph, node.as_str())),
+        Request::CoReachable { graph, node } => {
+            Respond::CoReachable(co_reachable(&graph, node.as_str()))
+        }
+        Request::MaximumFlow {
+            graph,
+            source,
+            sink,
+        } => Respond::MaximumFlow(maximum_flow(&graph, source.as_str(), sink.as_str())),
+        Request::MinimumSpanningForest { graph } => {
+            Respond::MinimumSpanningForest(minimum_spanning_forest(&graph))
+        }
+        Request::MinimumCostFlow {
+            network,
+            source,
+            sink,
+            amount,
+        } => Respond::MinimumCostFlow(minimum_cost_flow(
+            &network,
+            source.as_str(),
+            sink.as_str(),
+            amount,
+        )),
+        Request::Health {} => Respond::Health(status.health()),
+        Request::ServerInfo {} => Respond::ServerInfo(status.info()),
+    }
+}
+
+/// The name of the algorithm answering the request, for the logs
+pub fn algorithm(request: &Request) -> &'static str {
+    match request {
+        Request::ShortestPath { max_hops: None, .. } => "bellman_ford",
+        Request::ShortestPath { .. } => "hop_limited_bellman_ford",
+        Request::StronglyConnectedComponents { .. } => "tarjan",
+        Request::TopologicalSort { .. } => "kahn",
+        Request::Reachable { .. } | Request::CoReachable { .. } => "breadth_first_search",
+        Request::MaximumFlow { .. } => "dinic",
+        Request::MinimumSpanningForest { .. } => "kruskal",
+        Request::MinimumCostFlow { .. } => "successive_shortest_path",
+        Request::Health {} | Request::ServerInfo {} => "builtin",
+    }
+}
+
+/// How the request ended, for the logs: `ok`, `failed` when the algorithm rejected
+/// the graph, or the kind of the server error
+pub fn outcome(respond: &Respond) -> &'static str {
+    let failed = match respond {
+        Respond::ShortestPath(result) => result.is_err(),
+        Respond::TopologicalSort(result) => result.is_err(),
+        Respond::MaximumFlow(result) => result.is_err(),
+        Respond::MinimumSpanningForest(result) => result.is_err(),
+        Respond::MinimumCostFlow(result) => result.is_err(),
+        Respond::StronglyConnectedComponents(_)
+        | Respond::Reachable(_)
+        | Respond::CoReachable(_)
+        | Respond::Health(_)
+        | Respond::ServerInfo(_) => false,
+        Respond::Error(ServerError::Corrupted) => return "corrupted",
+        Respond::Error(ServerError::Forbidden(_)) => return "forbidden",
+        Respond::Error(ServerError::GoingAway) => return "going_away",
+        Respond::Error(ServerError::Overloaded(_)) => return "overloaded",
+        Respond::Error(ServerError::Invalid(_)) => return "invalid_request",
+    };
+    if failed {
+        "failed"
+    } else {
+        "ok"
+    }
+}
+
 /// The packet transformation method.
-pub fn process_packet(packet: Packet) -> Option<Packet> {
+/// The requests which can not be decoded or exceed the limits are answered as invalid,
+/// the requests of the methods missing from the agreed `methods` are forbidden,
+/// and the ones exceeding the work budget of the throttle are overloaded.
+pub fn process_packet(
+    packet: Packet,
+    options: &DecodeOptions,
+    methods: &[String],
+    principal: Option<&Principal>,
+    context: &Context,
+) -> Packet {
     let Packet { id, payload } = packet;
-    let (graph, start, end) = decode_request(payload)?;
-    println!("request : {:?}", (&graph, &start, &end));
-    let result = shortest_path(&graph, start.as_str(), end.as_str());
-    println!("result : {:?}", result);
-    Some(Packet {
+    let span = info_span!(
+        "request",
+        id,
+        method = field::Empty,
+        graph_size = field::Empty,
+        algorithm = field::Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let request = match decode_request(payload, options) {
+        Ok(request) => request,
+        Err(err) => {
+            warn!(%err, "invalid request");
+            context.metrics.error("invalid_request");
+            let respond = Respond::Error(ServerError::Invalid(err.to_string()));
+            return Packet {
+                id,
+                payload: encode_respond(&respond, options.encoding),
+            };
+        }
+    };
+    let method = request.method();
+    let graph_size = request.graph_size();
+    span.record("method", method);
+    span.record("graph_size", graph_size);
+    span.record("algorithm", algorithm(&request));
+    // the graphs may be huge, they are only logged when tracing
+    trace!(?request);
+    let authorized = if !methods.iter().any(|agreed| agreed == method) {
+        let message = format!("The method {} is not agreed.", method);
+        Err(ServerError::Forbidden(message))
+    } else {
+        principal.map_or(Ok(()), |principal| principal.authorize(&request))
+    };
+    let work = request.work();
+    let respond = match authorized.and_then(|()| context.throttle.reserve(work)) {
+        Ok(_reserved) => process_request(request, &context.status, &context.cache),
+        Err(err) => Respond::Error(err),
+    };
+    trace!(?respond);
+    let duration = started.elapsed();
+    let outcome = outcome(&respond);
+    info!(
+        duration_us = duration.as_micros() as u64,
+        outcome, "request answered"
+    );
+    context
+        .metrics
+        .observe(method, graph_size, outcome, duration);
+    Packet {
         id,
-        payload: encode_respond(result),
-    })
+        payload: encode_respond(&respond, options.encoding),
+    }
+}
+
+#[test]
+fn process_packet_log_test() {
+    use std::io::{Result, Write};
+    use std::sync::{Arc, Mutex};
+    use tracing::Level;
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let options = DecodeOptions::default();
+    let graph = Graph::from_edges(&[("secret", "b", 1.0), ("b", "secret", 1.0)]);
+    let request = Request::TopologicalSort { graph };
+    let methods = [String::from("topological_sort")];
+    let context = Context::default();
+    tracing::subscriber::with_default(subscriber, || {
+        for methods in [&methods[..], &[]] {
+            let payload = encode_request(&request, options.encoding).unwrap();
+            let packet = Packet { id: 7, payload };
+            process_packet(packet, &options, methods, None, &context);
+        }
+    });
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = logs.lines().collect();
+    assert_eq!(lines.len(), 2);
+    for field in &[
+        "id=7",
+        "topological_sort",
+        "graph_size=2",
+        "kahn",
+        "duration_us=",
+    ] {
+        assert!(
+            lines[0].contains(field),
+            "{} is missing in {}",
+            field,
+            lines[0]
+        );
+    }
+    assert!(lines[0].contains("outcome=\"failed\""));
+    assert!(lines[1].contains("outcome=\"forbidden\""));
+    // the graph is only logged when tracing
+    assert!(!logs.contains("secret"));
+}
+
+#[test]
+fn invalid_request_test() {
+    let options = DecodeOptions {
+        limits: Limits {
+            max_string_length: 4,
+            ..Limits::default()
+        },
+        ..DecodeOptions::default()
+    };
+    let graph = Graph::from_edges(&[("a", "b", 1.0)]);
+    let request = Request::Reachable {
+        graph,
+        node: String::from("abcde"),
+    };
+    let methods = [String::from("reachable")];
+    let context = Context::default();
+    let payload = encode_request(&request, options.encoding).unwrap();
+    // a string over the limit, then a truncated payload
+    for payload in [payload.clone(), payload.slice_to(3)] {
+        let packet = Packet { id: 7, payload };
+        let Packet { id, payload } = process_packet(packet, &options, &methods, None, &context);
+        // the connection stays open and the client receives the reason
+        assert_eq!(id, 7);
+        match decode_respond(payload, &DecodeOptions::default()) {
+            Ok(Respond::Error(ServerError::Invalid(_))) => {}
+            respond => panic!("unexpected respond {:?}", respond),
+        }
+    }
+    assert!(context
+        .metrics
+        .encode()
+        .contains(r#"rtrpc_errors_total{kind="invalid_request"} 2"#));
 }
@@ -0,0 +1,418 @@
+use crate::core::bellman_ford;
+use rtrpc_common::*;
+use std::collections::{HashMap, VecDeque};
+
+/// An arc in the residual network, the arc `i ^ 1` is its reverse.
+struct Arc {
+    target: usize,
+    residual: f64,
+    cost: f64,
+}
+
+/// The residual network built from the graph, with nodes sorted by name.
+struct Network<'a> {
+    names: Vec<&'a str>,
+    index: HashMap<&'a str, usize>,
+    arcs: Vec<Arc>,
+    adjacency: Vec<Vec<usize>>,
+    /// The capacity of each edge of the graph, whose forward arc is `2 * i`
+    capacities: Vec<f64>,
+}
+
+impl<'a> Network<'a> {
+    fn new(graph: &'a Graph) -> Result<Self, InvalidWeight> {
+        let mut names: Vec<&str> = graph.nodes().map(String::as_str).collect();
+        names.sort();
+        let mut edges: Vec<(&str, &str, f64, f64)> = Vec::new();
+        for &node in &names {
+            for (target, capacity) in graph.edges(node).unwrap() {
+                edges.push((node, target, *capacity, 0.0));
+            }
+        }
+        Self::from_edges(names, edges)
+    }
+
+    fn with_costs(network: &'a FlowNetwork) -> Result<Self, InvalidWeight> {
+        let mut names: Vec<&str> = network.nodes().map(String::as_str).collect();
+        names.sort();
+        let mut edges: Vec<(&str, &str, f64, f64)> = Vec::new();
+        for &node in &names {
+            for (target, capacity, cost) in network.edges(node).unwrap() {
+                edges.push((node, target, *capacity, *cost));
+            }
+        }
+        Self::from_edges(names, edges)
+    }
+
+    /// Build the network from the sorted nodes and the edges as `(start, end, capacity, cost)`.
+    fn from_edges(
+        names: Vec<&'a str>,
+        edges: Vec<(&'a str, &'a str, f64, f64)>,
+    ) -> Result<Self, InvalidWeight> {
+        let index: HashMap<&str, usize> = names.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        let mut network = Network {
+            arcs: Vec::new(),
+            adjacency: names.iter().map(|_| Vec::new()).collect(),
+            capacities: Vec::new(),
+            names,
+            index,
+        };
+        for (start, end, capacity, cost) in edges {
+            if !(capacity >= 0.0 && capacity.is_finite()) {
+                return Err(InvalidWeight(
+                    String::from(start),
+                    String::from(end),
+                    capacity,
+                ));
+            }
+            let (i, j) = (network.index[start], network.index[end]);
+            network.adjacency[i].push(network.arcs.len());
+            network.arcs.push(Arc {
+                target: j,
+                residual: capacity,
+                cost,
+            });
+            network.adjacency[j].push(network.arcs.len());
+            network.arcs.push(Arc {
+                target: i,
+                residual: 0.0,
+                cost: -cost,
+            });
+            network.capacities.push(capacity);
+        }
+        Ok(network)
+    }
+
+    /// The distance from the source of each node using the arcs with residual,
+    /// `None` for the nodes which can not be reached.
+    fn levels(&self, source: usize) -> Vec<Option<usize>> {
+        let mut level: Vec<Option<usize>> = vec![None; self.names.len()];
+        let mut queue: VecDeque<usize> = VecDeque::new();
+        level[source] = Some(0);
+        queue.push_back(source);
+        while let Some(node) = queue.pop_front() {
+            for &arc in &self.adjacency[node] {
+                let Arc {
+                    target, residual, ..
+                } = self.arcs[arc];
+                if residual > 0.0 && level[target].is_none() {
+                    level[target] = Some(level[node].unwrap() + 1);
+                    queue.push_back(target);
+                }
+            }
+        }
+        level
+    }
+
+    /// The flow on each edge as `(start, end, flow)`.
+    fn flows(&self) -> Vec<(String, String, f64)> {
+        let mut flows: Vec<(String, String, f64)> = Vec::new();
+        for (edge, capacity) in self.capacities.iter().enumerate() {
+            let arc = &self.arcs[2 * edge];
+            let start = self.names[self.arcs[2 * edge + 1].target];
+            let end = self.names[arc.target];
+            flows.push((
+                String::from(start),
+                String::from(end),
+                capacity - arc.residual,
+            ));
+        }
+        flows
+    }
+
+    /// Push a blocking flow along the level graph and returns its value.
+    fn blocking_flow(&mut self, source: usize, sink: usize, level: &mut [Option<usize>]) -> f64 {
+        let mut total = 0.0;
+        // the next arc to try for each node
+        let mut next: Vec<usize> = vec![0; self.names.len()];
+        let mut path: Vec<usize> = Vec::new();
+        let mut node = source;
+        loop {
+            if node == sink {
+                let bottleneck = path
+                    .iter()
+                    .map(|&arc| self.arcs[arc].residual)
+                    .fold(f64::INFINITY, f64::min);
+                for &arc in &path {
+                    self.arcs[arc].residual -= bottleneck;
+                    self.arcs[arc ^ 1].residual += bottleneck;
+                }
+                total += bottleneck;
+                // retreat to the tail of the first saturated arc
+                let saturated = path
+                    .iter()
+                    .position(|&arc| self.arcs[arc].residual <= 0.0)
+                    .unwrap();
+                node = self.arcs[path[saturated] ^ 1].target;
+                path.truncate(saturated);
+                continue;
+            }
+            let mut advanced = false;
+            while next[node] < self.adjacency[node].len() {
+                let arc = self.adjacency[node][next[node]];
+                let Arc {
+                    target, residual, ..
+                } = self.arcs[arc];
+                if residual > 0.0 && level[target] == level[node].map(|l| l + 1) {
+                    path.push(arc);
+                    node = target;
+                    advanced = true;
+                    break;
+                }
+                next[node] += 1;
+            }
+            if advanced {
+                continue;
+            }
+            // a dead end, remove it from the level graph
+            level[node] = None;
+            match path.pop() {
+                Some(arc) => {
+                    node = self.arcs[arc ^ 1].target;
+                    next[node] += 1;
+                }
+                None => break,
+            }
+        }
+        total
+    }
+}
+
+/// Calculate the maximum flow from source to sink with the Dinic's algorithm,
+/// using the edge weights as capacities.
+/// Returns `Err(InvalidWeight(..))` when an edge has a negative or non-finite capacity.
+/// Returns `Ok(None)` when one of source and sink is not in the graph, or they are the same.
+/// Returns `Ok(Some(flow))` otherwise, the flows are listed with the starts sorted
+/// and the edges of each start in the order of the graph.
+pub fn maximum_flow(
+    graph: &Graph,
+    source: &str,
+    sink: &str,
+) -> Result<Option<MaxFlow>, InvalidWeight> {
+    let mut network = Network::new(graph)?;
+    let (source, sink) = match (network.index.get(source), network.index.get(sink)) {
+        (Some(&source), Some(&sink)) if source != sink => (source, sink),
+        _ => return Ok(None),
+    };
+    let mut value = 0.0;
+    loop {
+        let mut level = network.levels(source);
+        if level[sink].is_none() {
+            break;
+        }
+        value += network.blocking_flow(source, sink, &mut level);
+    }
+    let flows = network.flows();
+    let level = network.levels(source);
+    let (source_side, sink_side): (Vec<usize>, Vec<usize>) =
+        (0..network.names.len()).partition(|&node| level[node].is_some());
+    let to_names = |nodes: Vec<usize>| {
+        nodes
+            .into_iter()
+            .map(|node| String::from(network.names[node]))
+            .collect()
+    };
+    Ok(Some(MaxFlow {
+        value,
+        flows,
+        source_side: to_names(source_side),
+        sink_side: to_names(sink_side),
+    }))
+}
+
+#[test]
+fn maximum_flow_test() {
+    let graph = Graph::from_edges(&[
+        ("s", "a", 10.0),
+        ("s", "c", 10.0),
+        ("a", "b", 4.0),
+        ("a", "c", 2.0),
+        ("a", "d", 8.0),
+        ("c", "d", 9.0),
+        ("b", "t", 10.0),
+        ("d", "b", 6.0),
+        ("d", "t", 10.0),
+        ("t", "s", 5.0),
+        ("x", "y", 1.0),
+    ]);
+    assert_eq!(maximum_flow(&graph, "s", "z"), Ok(None));
+    assert_eq!(maximum_flow(&graph, "s", "s"), Ok(None));
+    let flow = maximum_flow(&graph, "s", "t").unwrap().unwrap();
+    assert_eq!(flow.value, 19.0);
+    let nodes = |nodes: Vec<&str>| nodes.into_iter().map(String::from).collect::<Vec<String>>();
+    assert_eq!(flow.source_side, nodes(vec!["c", "s"]));
+    assert_eq!(flow.sink_side, nodes(vec!["a", "b", "d", "t", "x", "y"]));
+    assert_eq!(flow.flows.len(), 11);
+    for (start, end, value) in &flow.flows {
+        let capacity = graph
+            .edges(start)
+            .unwrap()
+            .iter()
+            .find(|(target, _)| target == end)
+            .unwrap()
+            .1;
+        assert!(*value >= 0.0 && *value <= capacity);
+    }
+    let outgoing: f64 = flow
+        .flows
+        .iter()
+        .filter(|(s, _, _)| s == "s")
+        .map(|f| f.2)
+        .sum();
+    assert_eq!(outgoing, 19.0);
+    let flow = maximum_flow(&graph, "x", "t").unwrap().unwrap();
+    assert_eq!(flow.value, 0.0);
+    let graph = Graph::from_edges(&[("a", "b", 1.0), ("b", "c", -1.0)]);
+    assert_eq!(
+        maximum_flow(&graph, "a", "c"),
+        Err(InvalidWeight(String::from("b"), String::from("c"), -1.0))
+    );
+}
+
+/// Calculate the minimum cost flow from source to sink with the successive shortest path
+/// algorithm, where the shortest paths in the residual network are found by `bellman_ford`
+/// so that negative costs are allowed. The flow stops growing when it reaches `amount`,
+/// or when no more flow can be sent, so the flow is the minimum cost maximum flow
+/// when `amount` is `None`, and its value may be less than `amount` otherwise.
+/// Returns `Err(..)` when `amount` is negative or NaN, an edge has a negative or non-finite
+/// capacity, an edge has a non-finite cost, or a negative cycle with positive capacity can be
+/// reached from the source.
+/// Returns `Ok(None)` when one of source and sink is not in the network, or they are the same.
+pub fn minimum_cost_flow(
+    network: &FlowNetwork,
+    source: &str,
+    sink: &str,
+    amount: Option<f64>,
+) -> Result<Option<MinCostFlow>, MinCostFlowError> {
+    if let Some(amount) = amount {
+        if amount < 0.0 || amount.is_nan() {
+            return Err(MinCostFlowError::InvalidAmount(amount));
+        }
+    }
+    for node in network.nodes() {
+        for (target, _, cost) in network.edges(node).unwrap() {
+            if !cost.is_finite() {
+                return Err(MinCostFlowError::InvalidCost(InvalidWeight(
+                    node.clone(),
+                    target.clone(),
+                    *cost,
+                )));
+            }
+        }
+    }
+    let mut residual = Network::with_costs(network).map_err(MinCostFlowError::InvalidCapacity)?;
+    let (source, sink) = match (residual.index.get(source), residual.index.get(sink)) {
+        (Some(&source), Some(&sink)) if source != sink => (source, sink),
+        _ => return Ok(None),
+    };
+    let amount = amount.unwrap_or(f64::INFINITY);
+    let (mut value, mut cost) = (0.0, 0.0);
+    while value < amount {
+        let arcs: Vec<usize> = (0..residual.arcs.len())
+            .filter(|&arc| residual.arcs[arc].residual > 0.0)
+            .collect();
+        let edges: Vec<(usize, usize, f64)> = arcs
+            .iter()
+            .map(|&arc| {
+                let Arc { target, cost, .. } = residual.arcs[arc];
+                (residual.arcs[arc ^ 1].target, target, cost)
+            })
+            .collect();
+        let (_, predecessor) = bellman_ford(residual.names.len(), &edges, source)
+            .map_err(|_| MinCostFlowError::NegativeCycle)?;
+        let mut path: Vec<usize> = Vec::new();
+        let mut node = sink;
+        while let Some(edge) = predecessor[node] {
+            path.push(arcs[edge]);
+            node = edges[edge].0;
+        }
+        if node != source {
+            break;
+        }
+        let bottleneck = path
+            .iter()
+            .map(|&arc| residual.arcs[arc].residual)
+            .fold(amount - value, f64::min);
+        for &arc in &path {
+            residual.arcs[arc].residual -= bottleneck;
+            residual.arcs[arc ^ 1].residual += bottleneck;
+            cost += bottleneck * residual.arcs[arc].cost;
+        }
+        value += bottleneck;
+    }
+    Ok(Some(MinCostFlow {
+        value,
+        cost,
+        flows: residual.flows(),
+    }))
+}
+
+#[test]
+fn minimum_cost_flow_test() {
+    let network = FlowNetwork::from_edges(&[
+        ("s", "a", 4.0, 1.0),
+        ("s", "b", 2.0, 4.0),
+        ("a", "b", 2.0, -2.0),
+        ("a", "t", 2.0, 6.0),
+        ("b", "t", 3.0, 1.0),
+    ]);
+    assert_eq!(minimum_cost_flow(&network, "s", "x", None), Ok(None));
+    let flow = minimum_cost_flow(&network, "s", "t", None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(flow.value, 5.0);
+    assert_eq!(flow.cost, 19.0);
+    let flow = minimum_cost_flow(&network, "s", "t", Some(2.0))
+        .unwrap()
+        .unwrap();
+    assert_eq!(flow.value, 2.0);
+    assert_eq!(flow.cost, 0.0);
+    let make_flows = |flows: Vec<(&str, &str, f64)>| {
+        flows
+            .into_iter()
+            .map(|(start, end, flow)| (String::from(start), String::from(end), flow))
+            .collect::<Vec<(String, String, f64)>>()
+    };
+    assert_eq!(
+        flow.flows,
+        make_flows(vec![
+            ("a", "b", 2.0),
+            ("a", "t", 0.0),
+            ("b", "t", 2.0),
+            ("s", "a", 2.0),
+            ("s", "b", 0.0),
+        ])
+    );
+    let network = FlowNetwork::from_edges(&[
+        ("s", "a", 1.0, 1.0),
+        ("a", "b", 1.0, -3.0),
+        ("b", "a", 1.0, 1.0),
+        ("a", "t", 1.0, 1.0),
+    ]);
+    assert_eq!(
+        minimum_cost_flow(&network, "s", "t", None),
+        Err(MinCostFlowError::NegativeCycle)
+    );
+    let network = FlowNetwork::from_edges(&[("s", "t", -1.0, 1.0)]);
+    assert_eq!(
+        minimum_cost_flow(&network, "s", "t", None),
+        Err(MinCostFlowError::InvalidCapacity(InvalidWeight(
+            String::from("s"),
+            String::from("t"),
+            -1.0
+        )))
+    );
+    let network = FlowNetwork::from_edges(&[("s", "t", 1.0, 1.0)]);
+    assert_eq!(
+        minimum_cost_flow(&network, "s", "t", Some(-1.0)),
+        Err(MinCostFlowError::InvalidAmount(-1.0))
+    );
+    assert!(matches!(
+        minimum_cost_flow(&network, "s", "t", Some(f64::NAN)),
+        Err(MinCostFlowError::InvalidAmount(amount)) if amount.is_nan()
+    ));
+    let flow = minimum_cost_flow(&network, "s", "t", Some(0.0))
+        .unwrap()
+        .unwrap();
+    assert_eq!(flow.value, 0.0);
+}
@@ -0,0 +1,369 @@
+use crate::auth::{now, Policy, Principal};
+use crate::context::Context;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Response, StatusCode};
+use rtrpc_common::*;
+use serde_json::{Map, Value};
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Instant;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tracing::{error, field, info, info_span, trace, Span};
+
+/// A gateway serving the methods over HTTP for the clients which can not speak the protocol.
+/// The request fields are the JSON body of `POST /<method>`, like the `json` encoding
+/// without the method name around them, and the respond is answered the same way.
+pub struct Gateway {
+    handle: Handle,
+    addr: SocketAddr,
+    limits: Limits,
+    policy: Option<Rc<Policy>>,
+    methods: Vec<String>,
+    context: Context,
+    workers: Option<CpuPool>,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Gateway {
+    /// Create a gateway with the handle and address used to listen
+    pub fn new(handle: Handle, addr: SocketAddr) -> Self {
+        Gateway {
+            handle,
+            addr,
+            limits: Limits::default(),
+            policy: None,
+            methods: METHODS.iter().map(|&method| String::from(method)).collect(),
+            context: Context::default(),
+            workers: None,
+            tls: None,
+        }
+    }
+    /// Set the limits of the body length and the graph size
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+    /// Require the token of a principal of the policy as `Authorization: Bearer <token>`,
+    /// the HMAC credentials are not accepted over HTTP
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Some(Rc::new(policy));
+        self
+    }
+    /// Serve only the methods of the list and the built-in ones,
+    /// the other ones are answered as forbidden
+    pub fn with_methods(mut self, methods: &[String]) -> Self {
+        self.methods.retain(|method| {
+            methods.contains(method) || BUILTIN_METHODS.contains(&method.as_str())
+        });
+        self
+    }
+    /// Process the requests with the context shared with the server,
+    /// the requests refused by its throttle are answered with 429 and 503
+    pub fn with_context(mut self, context: Context) -> Self {
+        self.context = context;
+        self
+    }
+    /// Compute the requests on the pool of threads instead of the reactor thread
+    pub fn with_workers(mut self, workers: CpuPool) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+    /// Accept only TLS connections, so that the bearer tokens are not sent in plaintext
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+    /// Consume the gateway and returns the serving Future,
+    /// fail when failed to listen the Ip address
+    pub fn serve(self) -> Result<impl Future<Item = (), Error = Error>, Error> {
+        let mut http = Http::new();
+        http.http1_only(true);
+        let handle = self.handle.clone();
+        let listener = TcpListener::bind(&self.addr, &self.handle)?;
+        let gateway = Rc::new(self);
+        let future = listener.incoming().for_each(move |(sock, peer)| {
+            let gateway = gateway.clone();
+            let span = info_span!("http_connection", %peer);
+            let connection_span = span.clone();
+            let tls = gateway.tls.clone();
+            let service = service_fn(move |request| {
+                let _entered = span.enter();
+                handle_request(request, &gateway, peer.ip())
+            });
+            let http = http.clone();
+            let connection = match tls {
+                Some(acceptor) => Either::A(
+                    acceptor
+                        .accept(sock)
+                        .map_err(|err| err.to_string())
+                        .and_then(move |stream| {
+                            let connection = http.serve_connection(stream, service);
+                            connection.map_err(|err| err.to_string())
+                        }),
+                ),
+                None => Either::B(
+                    http.serve_connection(sock, service)
+                        .map_err(|err| err.to_string()),
+                ),
+            };
+            handle.spawn(connection.map_err(move |err| {
+                connection_span.in_scope(|| error!(%err, "connection failed"))
+            }));
+            Ok(())
+        });
+        Ok(future)
+    }
+}
+
+/// Read the body of the request and answer it in the span of the connection,
+/// the body is dropped as soon as it exceeds the frame length limit.
+/// The request is computed on the workers when there are some, like by the server.
+/// The built-in methods are also answered to `GET` without authentication for the probes.
+/// The requests per second are counted by principal, or by IP address without authentication.
+fn handle_request(
+    request: hyper::Request<Body>,
+    gateway: &Rc<Gateway>,
+    ip: IpAddr,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+    let (limits, context) = (&gateway.limits, &gateway.context);
+    let method = String::from(request.uri().path().trim_start_matches('/'));
+    if !METHODS.contains(&method.as_str()) {
+        let message = format!("Unknown method {}.", method);
+        return Either::A(future::ok(error(StatusCode::NOT_FOUND, message)));
+    }
+    if !gateway.methods.contains(&method) {
+        let message = format!("The method {} is disabled.", method);
+        return Either::A(future::ok(error(StatusCode::FORBIDDEN, message)));
+    }
+    if BUILTIN_METHODS.contains(&method.as_str()) && request.method() == Method::GET {
+        let respond = call(&method, b"{}", limits, None, context);
+        return Either::A(future::ok(respond));
+    }
+    if request.method() != Method::POST {
+        let message = String::from("Use POST with the request as the JSON body.");
+        return Either::A(future::ok(error(StatusCode::METHOD_NOT_ALLOWED, message)));
+    }
+    let principal = match &gateway.policy {
+        Some(policy) => match authenticate(&request, policy) {
+            Ok(principal) => Some(principal.clone()),
+            Err(message) => return Either::A(future::ok(error(StatusCode::UNAUTHORIZED, message))),
+        },
+        None => None,
+    };
+    let client = match &principal {
+        Some(principal) => principal.name.clone(),
+        None => ip.to_string(),
+    };
+    if let Err(err) = context.throttle.admit(&client, Instant::now()) {
+        context.metrics.error("overloaded");
+        let message = err.to_string();
+        return Either::A(future::ok(error(StatusCode::TOO_MANY_REQUESTS, message)));
+    }
+    let limits = limits.clone();
+    let context = context.clone();
+    let workers = gateway.workers.clone();
+    let max_length = limits.max_frame_length;
+    let body = request
+        .into_body()
+        .fold(Some(Vec::new()), move |body, chunk| {
+            let body = body.filter(|body| body.len() + chunk.len() <= max_length);
+            Ok::<_, hyper::Error>(body.map(|mut body| {
+                body.extend_from_slice(&chunk);
+                body
+            }))
+        });
+    let span = Span::current();
+    Either::B(body.and_then(move |body| {
+        let body = match body {
+            Some(body) => body,
+            None => {
+                let message = format!("The body exceeds the limit {}.", max_length);
+                let _entered = span.enter();
+                return Either::A(future::ok(error(StatusCode::PAYLOAD_TOO_LARGE, message)));
+            }
+        };
+        let queued = context.metrics.queued();
+        // the work reserved by call is kept until the respond is computed
+        let compute = move || {
+            drop(queued);
+            let _entered = span.enter();
+            Ok(call(&method, &body, &limits, principal.as_ref(), &context))
+        };
+        match workers {
+            Some(workers) => Either::B(Either::A(workers.spawn_fn(compute))),
+            None => Either::B(Either::B(future::result(compute()))),
+        }
+    }))
+}
+
+/// Find the principal of the bearer token of the request
+fn authenticate<'a>(
+    request: &hyper::Request<Body>,
+    policy: &'a Policy,
+) -> Result<&'a Principal, String> {
+    let token = request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let credentials = token.map(|token| Credentials::Token(String::from(token)));
+    policy.authenticate(credentials.as_ref(), now())
+}
+
+/// Process the JSON body as the fields of the method
+fn call(
+    method: &str,
+    body: &[u8],
+    limits: &Limits,
+    principal: Option<&Principal>,
+    context: &Context,
+) -> Response<Body> {
+    let span = info_span!(
+        "request",
+        method,
+        graph_size = field::Empty,
+        algorithm = field::Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let fields: Value = match serde_json::from_slice(body) {
+        Ok(fields) => fields,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", err)),
+    };
+    let mut tagged = Map::new();
+    tagged.insert(String::from(method), fields);
+    let request: Request = match serde_json::from_value(Value::Object(tagged)) {
+        Ok(request) => request,
+        Err(err) => return error(StatusCode::BAD_REQUEST, format!("Invalid request: {}", err)),
+    };
+    let graph_size = request.graph_size();
+    span.record("graph_size", graph_size);
+    span.record("algorithm", crate::core::algorithm(&request));
+    if request.graph_size() > limits.max_graph_edges {
+        let message = format!(
+            "The graph size of {} exceeds the limit {}.",
+            request.graph_size(),
+            limits.max_graph_edges
+        );
+        return error(StatusCode::PAYLOAD_TOO_LARGE, message);
+    }
+    if let Err(err) = check_max_hops(&request, limits) {
+        return error(StatusCode::PAYLOAD_TOO_LARGE, err.to_string());
+    }
+    if let Some(Err(err)) = principal.map(|principal| principal.authorize(&request)) {
+        return error(StatusCode::FORBIDDEN, err.to_string());
+    }
+    let _reserved = match context.throttle.reserve(request.work()) {
+        Ok(reserved) => reserved,
+        Err(err) => {
+            context.metrics.error("overloaded");
+            return error(StatusCode::SERVICE_UNAVAILABLE, err.to_string());
+        }
+    };
+    // the graphs may be huge, they are only logged when tracing
+    trace!(?request);
+    let respond = crate::core::process_request(request, &context.status, &context.cache);
+    trace!(?respond);
+    let duration = started.elapsed();
+    let outcome = crate::core::outcome(&respond);
+    info!(
+        duration_us = duration.as_micros() as u64,
+        outcome, "request answered"
+    );
+    context
+        .metrics
+        .observe(method, graph_size, outcome, duration);
+    if !respond.is_finite() {
+        let message = EncodeError::NonFinite.to_string();
+        return error(StatusCode::UNPROCESSABLE_ENTITY, message);
+    }
+    // the respond is tagged with the method like the request
+    let value = match serde_json::to_value(&respond) {
+        Ok(Value::Object(tagged)) => tagged.into_iter().next().map(|(_, value)| value),
+        _ => None,
+    };
+    json(StatusCode::OK, &value.unwrap_or(Value::Null))
+}
+
+fn json(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: String) -> Response<Body> {
+    info!(status = status.as_u16(), %message, "request rejected");
+    let mut body = Map::new();
+    body.insert(String::from("error"), Value::String(message));
+    json(status, &Value::Object(body))
+}
+
+#[test]
+fn call_test() {
+    use crate::throttle::{Quotas, Throttle};
+    use std::sync::Arc;
+
+    let context = Context::default();
+    let limits = Limits {
+        max_graph_edges: 2,
+        ..Limits::default()
+    };
+    let send = |method, body: &[u8], principal, context| {
+        let response = call(method, body, &limits, principal, context);
+        let status = response.status();
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+    let body = br#"{"graph":[["a","b",1.0],["b","c",2.0]],"start":"a","end":"c","max_hops":null}"#;
+    assert_eq!(
+        send("shortest_path", body, None, &context),
+        (StatusCode::OK, String::from(r#"{"Ok":["a","b","c"]}"#))
+    );
+    let body = br#"{"graph":[["a","b",1.0],["b","a",2.0]]}"#;
+    assert_eq!(
+        send("topological_sort", body, None, &context),
+        (StatusCode::OK, String::from(r#"{"Err":["a","b"]}"#))
+    );
+    let (status, _) = send("reachable", body, None, &context);
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send("reachable", b"{", None, &context);
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body = br#"{"graph":[["a","b",1.0],["b","c",2.0],["c","a",3.0]]}"#;
+    let (status, _) = send("topological_sort", body, None, &context);
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let principal = Principal {
+        name: String::from("router"),
+        token: None,
+        hmac_secret: None,
+        methods: Some(vec![String::from("reachable")]),
+        max_graph_edges: None,
+    };
+    let body = br#"{"graph":[["a","b",1.0]]}"#;
+    let (status, _) = send("topological_sort", body, Some(&principal), &context);
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = send("health", b"{}", None, &context);
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"live":true,"ready":true}"#);
+
+    let busy = Context {
+        throttle: Arc::new(Throttle::new(Quotas {
+            max_work: Some(2),
+            ..Quotas::default()
+        })),
+        ..Context::default()
+    };
+    let _reserved = busy.throttle.reserve(1).unwrap();
+    let body = br#"{"graph":[["a","b",1.0],["b","a",2.0]]}"#;
+    let (status, _) = send("topological_sort", body, None, &busy);
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let (status, _) = send("health", b"{}", None, &busy);
+    assert_eq!(status, StatusCode::OK);
+}
@@ -1,19 +1,151 @@
+mod analysis;
+mod auth;
+mod cache;
+mod config;
+mod context;
 mod core;
+mod flow;
+mod http;
+mod metrics;
 mod server;
-use std::env::args;
+mod spanning;
+mod status;
+mod throttle;
+use auth::Policy;
+use cache::Cache;
+use clap::Parser;
+use config::{Cli, Config};
+use context::Context;
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use http::*;
+use metrics::Metrics;
+use rtrpc_common::tls_acceptor;
 use server::*;
+use status::Status;
+use std::fmt::Display;
+use std::process::exit;
+use std::sync::Arc;
+use throttle::Throttle;
 use tokio_core::reactor::Core;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::{fmt, prelude::*};
 
 fn main() {
+    let cli = Cli::parse();
+    let check = cli.check;
+    let config = Config::resolve(cli).unwrap_or_else(|err| fail(err));
+    let policy = config.auth_file.as_ref().map(|path| {
+        Policy::load(path)
+            .unwrap_or_else(|err| fail(format!("Can not load the principals: {}", err)))
+    });
+    let tls = config.tls.as_ref().map(|tls| {
+        tls_acceptor(&tls.cert, &tls.key, tls.client_ca.as_deref())
+            .unwrap_or_else(|err| fail(format!("Can not load the TLS certificate: {}", err)))
+    });
+    if check {
+        println!("The configuration is valid.");
+        return;
+    }
+    // the dependencies are quieter than the server
+    let level = config.log_level();
+    let filter = Targets::new()
+        .with_target("rtrpc", level)
+        .with_default(level.min(LevelFilter::INFO));
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(filter)
+        .init();
+    if tls.is_none() && policy.as_ref().is_some_and(Policy::has_tokens) {
+        warn!("the tokens of the principals are sent in plaintext without TLS");
+    }
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let argvec = args().collect::<Vec<_>>();
-    let arg1 = match argvec.len() {
-        1=>panic!("IP address not provided"),
-        _=>&argvec[1]
+    let metrics = Arc::new(Metrics::new());
+    let context = Context {
+        status: Arc::new(Status::new(&config.methods)),
+        throttle: Arc::new(Throttle::new(config.quotas.clone())),
+        cache: Arc::new(Cache::new(config.cache_size).with_metrics(metrics.clone())),
+        metrics,
     };
-    let addr = arg1.parse().expect("Invalid IP address");
-    let server = Server::new(handle, addr);
-    core.run(server.serve().expect("Can not listen to the address"))
-        .unwrap();
+    if let Some(metrics_addr) = config.metrics {
+        let endpoint = metrics::serve(&handle, &metrics_addr, context.metrics.clone())
+            .unwrap_or_else(|err| fail(format!("Can not listen to {}: {}", metrics_addr, err)));
+        handle.spawn(endpoint.map_err(|err| error!("{}", err)));
+    }
+    let workers = config.workers.map(CpuPool::new);
+    if let Some(http_addr) = config.http {
+        let mut gateway = Gateway::new(handle.clone(), http_addr)
+            .with_limits(config.limits.clone())
+            .with_methods(&config.methods)
+            .with_context(context.clone());
+        if let Some(workers) = &workers {
+            gateway = gateway.with_workers(workers.clone());
+        }
+        if let Some(tls) = &tls {
+            gateway = gateway.with_tls(tls.clone());
+        }
+        if let Some(policy) = &policy {
+            gateway = gateway.with_policy(policy.clone());
+        }
+        let gateway = gateway
+            .serve()
+            .unwrap_or_else(|err| fail(format!("Can not listen to {}: {}", http_addr, err)));
+        handle.spawn(gateway.map_err(|err| error!("{}", err)));
+    }
+    let mut addrs = config.listen.iter().cloned();
+    let mut server = Server::new(handle, addrs.next().unwrap())
+        .with_limits(config.limits.clone())
+        .with_lossy_utf8(config.lossy_utf8)
+        .with_compression_threshold(config.compression_threshold)
+        .with_methods(&config.methods)
+        .with_context(context)
+        .with_handshake_timeout(config.handshake_timeout())
+        .with_idle_timeout(config.idle_timeout())
+        .with_shutdown(shutdown_signal(), config.shutdown_timeout());
+    for addr in addrs {
+        server = server.with_address(addr);
+    }
+    for addr in &config.websocket {
+        server = server.with_websocket(addr.clone());
+    }
+    if let Some(workers) = workers {
+        server = server.with_workers(workers);
+    }
+    if let Some(tls) = tls {
+        server = server.with_tls(tls);
+    }
+    if let Some(policy) = policy {
+        server = server.with_policy(policy);
+    }
+    let serving = server
+        .serve()
+        .unwrap_or_else(|err| fail(format!("Can not listen to the addresses: {}", err)));
+    for addr in config.listen.iter().chain(&config.websocket) {
+        info!("listening on {}", addr);
+    }
+    if let Err(err) = core.run(serving) {
+        fail(err);
+    }
+}
+
+/// Print the error and exit with a failure status
+fn fail(message: impl Display) -> ! {
+    eprintln!("rtrpc: {}", message);
+    exit(1)
+}
+
+/// Resolved on the first SIGINT or SIGTERM
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    let interrupt = Signal::new(SIGINT).flatten_stream();
+    let terminate = Signal::new(SIGTERM).flatten_stream();
+    interrupt
+        .select(terminate)
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| error!("{}", err))
 }
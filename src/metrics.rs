@@ -0,0 +1,240 @@
+use futures::{future, Future, Stream};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Response, StatusCode};
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tracing::error;
+
+/// The metrics of the server, shared by the connections, the workers and the gateway
+pub struct Metrics {
+    registry: Registry,
+    /// The answered requests by method and outcome
+    requests: IntCounterVec,
+    /// The errors by kind, which are not answered for some of them
+    errors: IntCounterVec,
+    /// The time to decode and answer the requests by method
+    durations: HistogramVec,
+    /// The number of edges in the requests by method
+    graph_sizes: HistogramVec,
+    connections: IntGauge,
+    /// The requests waiting for a worker thread
+    queue_depth: IntGauge,
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    /// The approximate bytes held by the cache
+    cache_size: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("rtrpc_requests_total", "The answered requests"),
+            &["method", "outcome"],
+        )
+        .unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "rtrpc_errors_total",
+                "The errors of the requests and connections",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let durations = HistogramVec::new(
+            HistogramOpts::new(
+                "rtrpc_request_duration_seconds",
+                "The time to decode and answer the requests",
+            )
+            .buckets(exponential_buckets(0.0001, 4.0, 10).unwrap()),
+            &["method"],
+        )
+        .unwrap();
+        let graph_sizes = HistogramVec::new(
+            HistogramOpts::new("rtrpc_graph_edges", "The number of edges of the requests")
+                .buckets(exponential_buckets(1.0, 8.0, 8).unwrap()),
+            &["method"],
+        )
+        .unwrap();
+        let connections =
+            IntGauge::new("rtrpc_connections_active", "The connections being served").unwrap();
+        let queue_depth = IntGauge::new(
+            "rtrpc_queue_depth",
+            "The requests waiting for a worker thread",
+        )
+        .unwrap();
+        let cache_hits = IntCounter::new(
+            "rtrpc_cache_hits_total",
+            "The shortest paths answered from the cache",
+        )
+        .unwrap();
+        let cache_misses = IntCounter::new(
+            "rtrpc_cache_misses_total",
+            "The shortest paths computed for a missing cache entry",
+        )
+        .unwrap();
+        let cache_size = IntGauge::new(
+            "rtrpc_cache_size_bytes",
+            "The approximate bytes held by the cache",
+        )
+        .unwrap();
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(durations.clone())).unwrap();
+        registry.register(Box::new(graph_sizes.clone())).unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry.register(Box::new(cache_size.clone())).unwrap();
+        Metrics {
+            registry,
+            requests,
+            errors,
+            durations,
+            graph_sizes,
+            connections,
+            queue_depth,
+            cache_hits,
+            cache_misses,
+            cache_size,
+        }
+    }
+    /// Record an answered request, the outcomes other than `ok` and `failed` are errors
+    pub fn observe(&self, method: &str, graph_size: usize, outcome: &str, duration: Duration) {
+        self.requests.with_label_values(&[method, outcome]).inc();
+        self.durations
+            .with_label_values(&[method])
+            .observe(duration.as_secs_f64());
+        self.graph_sizes
+            .with_label_values(&[method])
+            .observe(graph_size as f64);
+        if outcome != "ok" && outcome != "failed" {
+            self.error(outcome);
+        }
+    }
+    /// Record an error of the kind
+    pub fn error(&self, kind: &str) {
+        self.errors.with_label_values(&[kind]).inc();
+    }
+    /// Record a lookup of the cache and the bytes it holds after it
+    pub fn cache_lookup(&self, hit: bool, size: usize) {
+        if hit {
+            self.cache_hits.inc();
+        } else {
+            self.cache_misses.inc();
+        }
+        self.cache_size.set(size as i64);
+    }
+    /// Count a connection until the returned guard is dropped
+    pub fn connection(&self) -> Gauged {
+        Gauged::new(self.connections.clone())
+    }
+    /// Count a request waiting for a worker thread until the returned guard is dropped
+    pub fn queued(&self) -> Gauged {
+        Gauged::new(self.queue_depth.clone())
+    }
+    /// The metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut text = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .unwrap();
+        String::from_utf8(text).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// The gauge is decreased when it is dropped
+pub struct Gauged(IntGauge);
+
+impl Gauged {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Gauged(gauge)
+    }
+}
+
+impl Drop for Gauged {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Serve the metrics in the Prometheus text format on `GET /metrics` of the address
+pub fn serve(
+    handle: &Handle,
+    addr: &SocketAddr,
+    metrics: Arc<Metrics>,
+) -> Result<impl Future<Item = (), Error = Error>, Error> {
+    let mut http = Http::new();
+    http.http1_only(true);
+    let handle = handle.clone();
+    let future = TcpListener::bind(addr, &handle)?
+        .incoming()
+        .for_each(move |(sock, _)| {
+            let metrics = metrics.clone();
+            let service = service_fn(move |request: hyper::Request<Body>| {
+                let response = match (request.method(), request.uri().path()) {
+                    (&Method::GET, "/metrics") => Response::builder()
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(Body::from(metrics.encode())),
+                    _ => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("Use GET /metrics.")),
+                };
+                future::ok::<_, hyper::Error>(response.unwrap())
+            });
+            handle.spawn(
+                http.serve_connection(sock, service)
+                    .map_err(|err| error!(%err, "metrics connection failed")),
+            );
+            Ok(())
+        });
+    Ok(future)
+}
+
+#[test]
+fn metrics_test() {
+    let metrics = Metrics::new();
+    metrics.observe("reachable", 3, "ok", Duration::from_millis(2));
+    metrics.observe("reachable", 1000, "forbidden", Duration::from_micros(10));
+    metrics.error("connection");
+    metrics.cache_lookup(false, 0);
+    metrics.cache_lookup(true, 100);
+    let connection = metrics.connection();
+    let queued = metrics.queued();
+    drop(queued);
+    let text = metrics.encode();
+    for line in &[
+        r#"rtrpc_requests_total{method="reachable",outcome="ok"} 1"#,
+        r#"rtrpc_requests_total{method="reachable",outcome="forbidden"} 1"#,
+        r#"rtrpc_errors_total{kind="forbidden"} 1"#,
+        r#"rtrpc_errors_total{kind="connection"} 1"#,
+        r#"rtrpc_request_duration_seconds_count{method="reachable"} 2"#,
+        r#"rtrpc_graph_edges_bucket{method="reachable",le="8"} 1"#,
+        "rtrpc_connections_active 1",
+        "rtrpc_queue_depth 0",
+        "rtrpc_cache_hits_total 1",
+        "rtrpc_cache_misses_total 1",
+        "rtrpc_cache_size_bytes 100",
+    ] {
+        assert!(text.lines().any(|l| l == *line), "{} is missing", line);
+    }
+    drop(connection);
+    assert!(metrics.encode().contains("rtrpc_connections_active 0"));
+}
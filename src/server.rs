@@ -1,49 +1,707 @@
-use futures::{Future, Stream};
-use rtrpc_common::PacketCodec;
+use crate::auth::{now, Policy, Principal};
+use crate::context::Context;
+use crate::throttle::Connected;
+use futures::future::{self, Either, Shared};
+use futures::sync::mpsc;
+use futures::{stream, Async, Future, Sink, Stream};
+use futures_cpupool::CpuPool;
+use rtrpc_common::*;
+use std::cell::Cell;
+use std::fs;
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
+use std::net::IpAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::codec::Framed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
 use tokio_core::net::TcpListener;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
+use tracing::{debug, error, info, info_span, warn, Span};
+
+/// The time given to the connections to finish after the shutdown by default
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// The time given to the new connections to send the hello packet by default
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The time an accepted connection may stay without request by default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The incoming connections of the listened addresses, with the address of their peer,
+/// or the path of the socket for the Unix domain sockets, and the IP address of the TCP peers
+type Incoming = Box<dyn Stream<Item = (Box<dyn Transport>, String, Option<IpAddr>), Error = Error>>;
+
+/// The future resolved when the server must shut down, shared by the connections
+type Shutdown = Shared<Box<dyn Future<Item = (), Error = ()>>>;
 
 /// A server used to recieve rpc request
 pub struct Server {
     handle: Handle,
-    addr: SocketAddr,
+    addrs: Vec<Address>,
+    features: Features,
+    options: DecodeOptions,
+    compression_threshold: usize,
+    websocket_addrs: Vec<Address>,
+    /// The threads computing the requests, the reactor thread when missing
+    workers: Option<CpuPool>,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    tls: Option<TlsAcceptor>,
+    policy: Option<Rc<Policy>>,
+    shutdown: Shutdown,
+    shutdown_timeout: Duration,
+    context: Context,
+    /// Held by each connection, so the server knows when they are all closed
+    drain: Option<mpsc::UnboundedSender<()>>,
 }
 
 impl Server {
-	/// Create a server with the handle and address used to listen
-    pub fn new(handle: Handle, addr: SocketAddr) -> Self {
-        Server { handle, addr }
-    }
-	/// Consume the server and returns the serving Future,
-	/// fail when failed to listen the Ip address
-    pub fn serve(self) -> Result<impl Future<Item = (), Error = Error>, Error> {
-        let future = TcpListener::bind(&self.addr, &self.handle)?
-            .incoming()
-            .for_each(move |(sock, _)| {
-                let (sink, stream) = Framed::new(sock, PacketCodec::new()).split();
-                let process_packet = |packet| {
-                    crate::core::process_packet(packet).ok_or(Error::new(
-                        ErrorKind::InvalidData,
-                        "Can not parse request packet.",
-                    ))
-                };
-                self.handle.spawn(
-                    stream
-                        .and_then(process_packet)
-                        .forward(sink)
-                        .then(|result| {
-                            match result {
-                                Ok(_) => {}
-                                Err(e) => println!("error: {}", e),
-                            }
-                            Ok(())
-                        }),
-                );
+    /// Create a server with the handle and address used to listen,
+    /// which is a TCP address or the path of a Unix domain socket
+    pub fn new(handle: Handle, addr: impl Into<Address>) -> Self {
+        Server {
+            handle,
+            addrs: vec![addr.into()],
+            features: Features::supported(),
+            options: DecodeOptions::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            websocket_addrs: Vec::new(),
+            workers: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            tls: None,
+            policy: None,
+            shutdown: (Box::new(future::empty()) as Box<dyn Future<Item = (), Error = ()>>)
+                .shared(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            context: Context::default(),
+            drain: None,
+        }
+    }
+    /// Also listen the address
+    pub fn with_address(mut self, addr: impl Into<Address>) -> Self {
+        self.addrs.push(addr.into());
+        self
+    }
+    /// Set the limits used to reject oversized frames and payloads
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+    /// Accept the invalid UTF-8 strings by replacing the invalid sequences,
+    /// instead of rejecting the request
+    pub fn with_lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.options.lossy_utf8 = lossy_utf8;
+        self
+    }
+    /// Set the size from which the responds are compressed,
+    /// when the client agrees on a compression
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+    /// Also accept the connections carried by WebSocket binary messages on the address
+    pub fn with_websocket(mut self, addr: impl Into<Address>) -> Self {
+        self.websocket_addrs.push(addr.into());
+        self
+    }
+    /// Serve only the methods of the list and the built-in ones,
+    /// the other ones are left out of the agreements
+    pub fn with_methods(mut self, methods: &[String]) -> Self {
+        self.features.methods.retain(|method| {
+            methods.contains(method) || BUILTIN_METHODS.contains(&method.as_str())
+        });
+        self
+    }
+    /// Compute the requests on the pool of threads instead of the reactor thread,
+    /// the pool may be shared with the HTTP gateway
+    pub fn with_workers(mut self, workers: CpuPool) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+    /// Set the time given to the new connections to send the hello packet
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+    /// Set the time an accepted connection may stay without request before it is closed,
+    /// the requests in flight keep it open
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /// Accept only TLS connections, on the WebSocket address too
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+    /// Require the clients to authenticate as one of the principals of the policy,
+    /// which limits the requests they can make
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Some(Rc::new(policy));
+        self
+    }
+    /// Process the requests with the metrics, the status, the throttle and the cache
+    /// of the context, the refused connections are answered as overloaded too
+    pub fn with_context(mut self, context: Context) -> Self {
+        self.context = context;
+        self
+    }
+    /// Shut down when the signal is resolved: stop accepting connections, answer the
+    /// requests already read, and close the connections with a going away notice.
+    /// The serving future is resolved when they are closed or after the timeout.
+    pub fn with_shutdown<F>(mut self, signal: F, timeout: Duration) -> Self
+    where
+        F: Future<Item = (), Error = ()> + 'static,
+    {
+        self.shutdown = (Box::new(signal) as Box<dyn Future<Item = (), Error = ()>>).shared();
+        self.shutdown_timeout = timeout;
+        self
+    }
+    /// Consume the server and returns the serving Future,
+    /// fail when failed to listen the address
+    pub fn serve(mut self) -> Result<impl Future<Item = (), Error = Error>, Error> {
+        let direct = listen_all(&self.addrs, &self.handle)?;
+        let websocket = listen_all(&self.websocket_addrs, &self.handle)?;
+        let (drain, drained) = mpsc::unbounded();
+        self.drain = Some(drain);
+        let handle = self.handle.clone();
+        let status = self.context.status.clone();
+        let timeout = self.shutdown_timeout;
+        let shutdown = self.shutdown.clone();
+        let server = Rc::new(self);
+        let direct = {
+            let server = server.clone();
+            until(direct, shutdown.clone()).for_each(move |(sock, peer, ip)| {
+                let span = info_span!("connection", %peer);
+                let connected = server.connect(ip);
+                spawn_accepted(server.clone(), server.secure(sock), span, connected);
                 Ok(())
+            })
+        };
+        let websocket = until(websocket, shutdown).for_each(move |(sock, peer, ip)| {
+            let span = info_span!("connection", %peer, websocket = true);
+            let connected = server.connect(ip);
+            let max_message_size = websocket_message_size(&server.options.limits);
+            let accept = server
+                .secure(sock)
+                .and_then(move |sock| accept_websocket(sock, max_message_size));
+            spawn_accepted(server.clone(), accept, span, connected);
+            Ok(())
+        });
+        let accepting = direct.join(websocket).map(|_| ());
+        // the drained stream ends when the server and the connections dropped their senders
+        let draining = accepting.and_then(move |()| {
+            info!("shutting down");
+            status.set_ready(false);
+            let drained = drained.collect().then(|_| Ok(()));
+            let deadline = Timeout::new(timeout, &handle)?.map(|()| {
+                warn!("shutdown timed out, closing the remaining connections");
             });
-        Ok(future)
+            Ok(drained.select(deadline).map(|_| ()).map_err(|(err, _)| err))
+        });
+        Ok(draining.flatten())
+    }
+    /// Count a new connection of the IP address, the refused ones are still
+    /// served until the handshake to be told why
+    fn connect(&self, ip: Option<IpAddr>) -> Result<Option<Connected>, ServerError> {
+        ip.map(|ip| self.context.throttle.connect(ip)).transpose()
+    }
+    /// Finish the TLS handshake of a new connection when TLS is enabled
+    fn secure(
+        &self,
+        sock: Box<dyn Transport>,
+    ) -> impl Future<Item = Box<dyn Transport>, Error = Error> {
+        match &self.tls {
+            Some(acceptor) => Either::A(
+                acceptor
+                    .accept(sock)
+                    .map(|stream| Box::new(stream) as Box<dyn Transport>),
+            ),
+            None => Either::B(future::ok(sock)),
+        }
+    }
+    /// Serve the requests of a new connection until it is closed,
+    /// the requests are logged in the span of the connection.
+    /// The connection refused by the throttle is rejected in the handshake.
+    fn spawn_connection<T>(
+        self: &Rc<Self>,
+        sock: T,
+        span: Span,
+        connected: Result<Option<Connected>, ServerError>,
+    ) where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let drain = self.drain.clone();
+        let metrics = self.context.metrics.clone();
+        let connection = metrics.connection();
+        let (connected, refusal) = match connected {
+            Ok(connected) => (connected, None),
+            Err(err) => {
+                span.in_scope(|| warn!(%err, "connection refused"));
+                metrics.error("overloaded");
+                (None, Some(err))
+            }
+        };
+        let ip = connected.as_ref().map(Connected::ip);
+        let server = self.clone();
+        let connection_span = span.clone();
+        self.handle.spawn(
+            self.admit(sock, refusal)
+                .and_then(move |accepted| match accepted {
+                    Some(accepted) => {
+                        Either::A(server.serve_requests(accepted, ip, connection_span))
+                    }
+                    None => Either::B(future::ok(())),
+                })
+                .then(move |result| {
+                    drop(drain);
+                    drop(connection);
+                    drop(connected);
+                    let _entered = span.enter();
+                    match result {
+                        Ok(_) => debug!("connection closed"),
+                        Err(err) => {
+                            metrics.error("connection");
+                            error!(%err, "connection failed")
+                        }
+                    }
+                    Ok(())
+                }),
+        );
+    }
+    /// Finish the handshake of a new connection, nothing is accepted when the server
+    /// shuts down first, and it fails after the handshake timeout
+    fn admit<T>(
+        &self,
+        sock: T,
+        refusal: Option<ServerError>,
+    ) -> impl Future<Item = Option<Accepted<T>>, Error = Error>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let framed = Framed::new(sock, PacketCodec::with_limits(&self.options.limits));
+        let policy = self.policy.clone();
+        // a connection still in the handshake is closed when the server shuts down
+        let stop = self.shutdown.clone().then(|_| Ok(None));
+        let deadline = future::result(Timeout::new(self.handshake_timeout, &self.handle))
+            .flatten()
+            .and_then(|()| Err(Error::new(ErrorKind::TimedOut, "The handshake timed out.")));
+        accept(framed, &self.features, &self.options, policy, refusal)
+            .map(Some)
+            .select(stop)
+            .map(|(accepted, _)| accepted)
+            .map_err(|(err, _)| err)
+            .select(deadline)
+            .map(|(accepted, _)| accepted)
+            .map_err(|(err, _)| err)
+    }
+    /// Set the codec of an accepted connection to the agreed features,
+    /// returns the options the requests are decoded with
+    fn decode<T>(
+        &self,
+        framed: &mut Framed<T, PacketCodec>,
+        agreement: &Agreement,
+    ) -> Result<DecodeOptions, Error> {
+        let encoding = Encoding::from_name(&agreement.encoding);
+        let compression = Compression::from_name(&agreement.compression);
+        let checksum = Checksum::from_name(&agreement.checksum);
+        match (encoding, compression, checksum) {
+            (Some(encoding), Some(compression), Some(checksum)) => {
+                let codec = framed.codec_mut();
+                codec.set_encoding(encoding);
+                codec.set_compression(compression, self.compression_threshold);
+                codec.set_checksum(checksum);
+                Ok(DecodeOptions {
+                    encoding,
+                    ..self.options.clone()
+                })
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unknown encoding {}, compression {} or checksum {} agreed.",
+                    agreement.encoding, agreement.compression, agreement.checksum
+                ),
+            )),
+        }
+    }
+    /// Answer the requests of an accepted connection until it is closed, idle,
+    /// or the server shuts down, in which case the client is told it goes away
+    fn serve_requests<T>(
+        &self,
+        (mut framed, agreement, principal): Accepted<T>,
+        ip: Option<IpAddr>,
+        span: Span,
+    ) -> impl Future<Item = (), Error = Error>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let options = match self.decode(&mut framed, &agreement) {
+            Ok(options) => options,
+            Err(err) => return Either::A(future::err(err)),
+        };
+        span.in_scope(|| {
+            debug!(
+                encoding = %agreement.encoding,
+                compression = %agreement.compression,
+                checksum = %agreement.checksum,
+                principal = principal.as_ref().map(|principal| &principal.name[..]),
+                "connection accepted"
+            )
+        });
+        // the quotas of the requests are shared by the connections of the client
+        let client = match (&principal, ip) {
+            (Some(principal), _) => principal.name.clone(),
+            (None, Some(ip)) => ip.to_string(),
+            (None, None) => String::from("local"),
+        };
+        let in_flight = Rc::new(Cell::new(0));
+        let idle = match Timeout::new(self.idle_timeout, &self.handle) {
+            Ok(delay) => Idle {
+                timeout: self.idle_timeout,
+                delay,
+                in_flight: in_flight.clone(),
+                span: span.clone(),
+            },
+            Err(err) => return Either::A(future::err(err)),
+        };
+        let encoding = options.encoding;
+        let requests = Requests {
+            session: Arc::new(Session {
+                options,
+                methods: agreement.methods,
+                principal,
+                span,
+                context: self.context.clone(),
+            }),
+            client,
+            workers: self.workers.clone(),
+            in_flight,
+            max_in_flight: self.context.throttle.max_in_flight(),
+        };
+        // the next requests are read while the previous ones are computed,
+        // to answer the ones exceeding the requests in flight as overloaded
+        let concurrency = requests.max_in_flight.map_or(1, |limit| limit + 1);
+        let shutdown = self.shutdown.clone();
+        let (sink, stream) = framed.split();
+        let serve = until(idle.watch(stream), shutdown.clone())
+            .then(move |read| Ok::<_, Error>(requests.answer(read)))
+            .buffered(concurrency)
+            .forward(sink)
+            .and_then(move |(_, sink)| {
+                if shutdown.peek().is_none() {
+                    return Either::A(future::ok(()));
+                }
+                let notice = Packet {
+                    id: MAGIC,
+                    payload: encode_respond(&Respond::Error(ServerError::GoingAway), encoding),
+                };
+                Either::B(sink.send(notice).map(|_| ()))
+            });
+        Either::B(serve)
+    }
+}
+
+/// An accepted connection, with the agreed features and the authenticated principal
+type Accepted<T> = (Framed<T, PacketCodec>, Agreement, Option<Principal>);
+
+/// The requests of a connection being answered
+struct Requests {
+    session: Arc<Session>,
+    /// The principal, or the IP address, whose quotas the requests take
+    client: String,
+    workers: Option<CpuPool>,
+    in_flight: Rc<Cell<usize>>,
+    max_in_flight: Option<usize>,
+}
+
+impl Requests {
+    /// Answer a frame read: its quotas are reserved before it is computed,
+    /// and the refused requests and corrupted frames are answered with an error
+    fn answer(&self, read: Result<Packet, Error>) -> impl Future<Item = Packet, Error = Error> {
+        let packet = match read {
+            Ok(packet) => packet,
+            Err(err) => return Either::B(future::result(self.corrupted(err))),
+        };
+        match self.reserve() {
+            Ok(reserved) => Either::A(self.compute(packet).then(move |result| {
+                drop(reserved);
+                result
+            })),
+            Err(err) => {
+                let id = packet.id;
+                self.session
+                    .span
+                    .in_scope(|| info!(id, %err, "request refused"));
+                self.session.context.metrics.error("overloaded");
+                Either::B(future::ok(self.encode(id, err)))
+            }
+        }
+    }
+    /// Take a request of the quotas of the client and of the connection,
+    /// the request stays in flight until the guard is dropped
+    fn reserve(&self) -> Result<InFlight, ServerError> {
+        let in_flight = self.in_flight.get();
+        self.session
+            .admit(&self.client, in_flight, self.max_in_flight)?;
+        self.in_flight.set(in_flight + 1);
+        Ok(InFlight(self.in_flight.clone()))
+    }
+    /// Compute the request on the workers, or on the reactor thread without them
+    fn compute(&self, packet: Packet) -> impl Future<Item = Packet, Error = Error> {
+        let session = self.session.clone();
+        match &self.workers {
+            Some(workers) => {
+                let queued = session.context.metrics.queued();
+                Either::A(workers.spawn_fn(move || {
+                    drop(queued);
+                    Ok(session.process(packet))
+                }))
+            }
+            None => Either::B(future::ok(session.process(packet))),
+        }
+    }
+    /// The error respond of the request
+    fn encode(&self, id: u32, err: ServerError) -> Packet {
+        Packet {
+            id,
+            payload: encode_respond(&Respond::Error(err), self.session.options.encoding),
+        }
     }
+    /// Answer the corrupted frame instead of closing the connection, with the magic id
+    /// since the id read from it can not be trusted, the other errors are returned
+    fn corrupted(&self, err: Error) -> Result<Packet, Error> {
+        CorruptedFrame::from_io_error(&err).ok_or(err)?;
+        self.session.context.metrics.error("corrupted");
+        Ok(self.encode(MAGIC, ServerError::Corrupted))
+    }
+}
+
+/// A request in flight on a connection
+struct InFlight(Rc<Cell<usize>>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// What the requests of a connection are processed with, shared with the workers
+struct Session {
+    options: DecodeOptions,
+    /// The agreed methods, the other requests are forbidden
+    methods: Vec<String>,
+    principal: Option<Principal>,
+    /// The span of the connection, entered on the worker threads too
+    span: Span,
+    context: Context,
+}
+
+impl Session {
+    /// Take a request of the quotas of the client, before it is computed
+    fn admit(
+        &self,
+        client: &str,
+        in_flight: usize,
+        max_in_flight: Option<usize>,
+    ) -> Result<(), ServerError> {
+        match max_in_flight {
+            Some(limit) if in_flight >= limit => Err(ServerError::Overloaded(format!(
+                "The connection has {} requests in flight already.",
+                in_flight
+            ))),
+            _ => self.context.throttle.admit(client, Instant::now()),
+        }
+    }
+    fn process(&self, packet: Packet) -> Packet {
+        let _entered = self.span.enter();
+        let principal = self.principal.as_ref();
+        let (options, methods) = (&self.options, &self.methods);
+        crate::core::process_packet(packet, options, methods, principal, &self.context)
+    }
+}
+
+/// End the stream when it ends or when the server shuts down
+fn until<S>(stream: S, shutdown: Shutdown) -> impl Stream<Item = S::Item, Error = S::Error>
+where
+    S: Stream,
+{
+    let stop = shutdown.then(|_| Ok(None)).into_stream();
+    stream
+        .map(Some)
+        .chain(stream::once(Ok(None)))
+        .select(stop)
+        .take_while(|item| Ok(item.is_some()))
+        .filter_map(|item| item)
+}
+
+/// The clock of a connection without request, so the slow or silent clients
+/// do not hold their connection quota forever
+struct Idle {
+    timeout: Duration,
+    delay: Timeout,
+    in_flight: Rc<Cell<usize>>,
+    span: Span,
+}
+
+impl Idle {
+    /// End the stream of the requests when none is read for the timeout
+    /// while none is in flight, the partial frames do not count
+    fn watch<S>(mut self, mut stream: S) -> impl Stream<Item = S::Item, Error = S::Error>
+    where
+        S: Stream<Error = Error>,
+    {
+        stream::poll_fn(move || {
+            if let Async::Ready(item) = stream.poll()? {
+                self.delay.reset(Instant::now() + self.timeout);
+                return Ok(Async::Ready(item));
+            }
+            while self.delay.poll()?.is_ready() {
+                if self.in_flight.get() == 0 {
+                    self.span.in_scope(|| debug!("connection idle"));
+                    return Ok(Async::Ready(None));
+                }
+                self.delay.reset(Instant::now() + self.timeout);
+            }
+            Ok(Async::NotReady)
+        })
+    }
+}
+
+/// Serve the connection once it is accepted, the failed ones are dropped
+fn spawn_accepted<F>(
+    server: Rc<Server>,
+    accept: F,
+    span: Span,
+    connected: Result<Option<Connected>, ServerError>,
+) where
+    F: Future<Error = Error> + 'static,
+    F::Item: AsyncRead + AsyncWrite + 'static,
+{
+    let handle = server.handle.clone();
+    handle.spawn(accept.then(move |result| {
+        match result {
+            Ok(stream) => server.spawn_connection(stream, span, connected),
+            Err(err) => span.in_scope(|| warn!(%err, "can not accept the connection")),
+        }
+        Ok(())
+    }));
+}
+
+/// Listen all the addresses and return their incoming connections
+fn listen_all(addrs: &[Address], handle: &Handle) -> Result<Incoming, Error> {
+    let mut incoming: Incoming = Box::new(stream::empty());
+    for addr in addrs {
+        incoming = Box::new(incoming.select(listen(addr, handle)?));
+    }
+    Ok(incoming)
+}
+
+/// Listen the address and return the incoming connections
+fn listen(addr: &Address, handle: &Handle) -> Result<Incoming, Error> {
+    match addr {
+        Address::Tcp(addr) => {
+            let incoming = TcpListener::bind(addr, handle)?.incoming();
+            let incoming = incoming.map(|(sock, peer)| {
+                let sock = Box::new(sock) as Box<dyn Transport>;
+                (sock, peer.to_string(), Some(peer.ip()))
+            });
+            Ok(Box::new(incoming))
+        }
+        Address::Unix(path) => {
+            remove_stale_socket(path)?;
+            let incoming = UnixListener::bind(path)?.incoming();
+            let local = addr.to_string();
+            let incoming = incoming
+                .map(move |sock| (Box::new(sock) as Box<dyn Transport>, local.clone(), None));
+            Ok(Box::new(incoming))
+        }
+    }
+}
+
+/// Remove the socket file left by a previous server at the path,
+/// any other file is kept and fails the binding
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Authenticate the client when the server has a policy, and negotiate the features.
+/// The methods the principal may not call are removed from the agreement.
+fn welcome(
+    hello: &Hello,
+    features: &Features,
+    policy: Option<&Policy>,
+) -> (Welcome, Option<Principal>) {
+    let credentials = hello.credentials.as_ref();
+    let principal = match policy.map(|policy| policy.authenticate(credentials, now())) {
+        Some(Ok(principal)) => Some(principal.clone()),
+        Some(Err(reason)) => return (Welcome::Reject(reason), None),
+        None => None,
+    };
+    let welcome = match (negotiate(hello, features), &principal) {
+        (Welcome::Accept(mut agreement), Some(principal)) => {
+            agreement.methods.retain(|method| principal.allows(method));
+            Welcome::Accept(agreement)
+        }
+        (welcome, _) => welcome,
+    };
+    (welcome, principal)
+}
+
+/// Read the hello packet of a new connection and answer it, returns the connection,
+/// the agreed features and the authenticated principal when it is accepted.
+/// The hello is rejected with the refusal when there is one.
+fn accept<T>(
+    framed: Framed<T, PacketCodec>,
+    features: &Features,
+    options: &DecodeOptions,
+    policy: Option<Rc<Policy>>,
+    refusal: Option<ServerError>,
+) -> impl Future<Item = (Framed<T, PacketCodec>, Agreement, Option<Principal>), Error = Error>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let features = features.clone();
+    let options = options.clone();
+    framed
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(packet, framed)| {
+            let (welcome, principal) = match (packet, refusal) {
+                (Some(_), Some(refusal)) => (Welcome::Reject(refusal.to_string()), None),
+                (Some(Packet { id: MAGIC, payload }), None) => {
+                    match decode_hello(payload, &options) {
+                        Ok(hello) => welcome(&hello, &features, policy.as_deref()),
+                        Err(err) => (
+                            Welcome::Reject(format!("Can not parse hello packet: {}", err)),
+                            None,
+                        ),
+                    }
+                }
+                (Some(_), None) => (Welcome::Reject(String::from("Handshake required.")), None),
+                (None, _) => {
+                    return Either::A(future::err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed before handshake.",
+                    )))
+                }
+            };
+            let packet = Packet {
+                id: MAGIC,
+                payload: encode_welcome(&welcome),
+            };
+            Either::B(framed.send(packet).and_then(move |framed| match welcome {
+                Welcome::Accept(agreement) => Ok((framed, agreement, principal)),
+                Welcome::Reject(reason) => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Handshake rejected: {}", reason),
+                )),
+            }))
+        })
 }
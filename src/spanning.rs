@@ -0,0 +1,128 @@
+use rtrpc_common::*;
+use std::collections::HashMap;
+
+/// The disjoint set used to track the trees in the forest.
+struct DisjointSet {
+    parent: Vec<usize>,
+    rank: Vec<u8>,
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> Self {
+        DisjointSet {
+            parent: (0..size).collect(),
+            rank: vec![0; size],
+        }
+    }
+    fn find(&mut self, node: usize) -> usize {
+        let mut root = node;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut current = node;
+        while self.parent[current] != root {
+            let next = self.parent[current];
+            self.parent[current] = root;
+            current = next;
+        }
+        root
+    }
+    /// Merge the sets of the two nodes, returns false when they are already in the same set.
+    fn union(&mut self, first: usize, second: usize) -> bool {
+        let (first, second) = (self.find(first), self.find(second));
+        if first == second {
+            return false;
+        }
+        if self.rank[first] < self.rank[second] {
+            self.parent[first] = second;
+        } else {
+            self.parent[second] = first;
+            if self.rank[first] == self.rank[second] {
+                self.rank[first] += 1;
+            }
+        }
+        true
+    }
+}
+
+/// Calculate the minimum spanning forest with the Kruskal's algorithm,
+/// treating every edge as undirected. Self loops are never chosen,
+/// and the edges with the same weight are picked in the order of their names.
+/// Returns `Err(InvalidWeight(..))` when an edge has a NaN weight.
+pub fn minimum_spanning_forest(graph: &Graph) -> Result<SpanningForest, InvalidWeight> {
+    let mut names: Vec<&str> = graph.nodes().map(String::as_str).collect();
+    names.sort();
+    let index: HashMap<&str, usize> = names.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+    let mut edges: Vec<(&str, &str, f64)> = Vec::new();
+    for &node in &names {
+        for (target, weight) in graph.edges(node).unwrap() {
+            if weight.is_nan() {
+                return Err(InvalidWeight(String::from(node), target.clone(), *weight));
+            }
+            edges.push((node, target, *weight));
+        }
+    }
+    edges.sort_by(|(start1, end1, weight1), (start2, end2, weight2)| {
+        weight1
+            .partial_cmp(weight2)
+            .unwrap()
+            .then(start1.cmp(start2))
+            .then(end1.cmp(end2))
+    });
+    let mut trees = DisjointSet::new(names.len());
+    let mut forest = SpanningForest {
+        edges: Vec::new(),
+        total_weight: 0.0,
+        tree_count: names.len() as u32,
+    };
+    for (start, end, weight) in edges {
+        if trees.union(index[start], index[end]) {
+            forest
+                .edges
+                .push((String::from(start), String::from(end), weight));
+            forest.total_weight += weight;
+            forest.tree_count -= 1;
+        }
+    }
+    Ok(forest)
+}
+
+#[test]
+fn minimum_spanning_forest_test() {
+    let graph = Graph::from_edges(&[
+        ("a", "b", 1.0),
+        ("b", "c", 2.0),
+        ("a", "c", 4.0),
+        ("d", "c", 3.0),
+        ("c", "e", -4.0),
+        ("c", "e", 4.0),
+        ("f", "g", 4.0),
+        ("g", "f", -6.0),
+        ("f", "h", 2.0),
+        ("h", "f", 2.0),
+        ("i", "i", 1.0),
+    ]);
+    let make_edges = |edges: Vec<(&str, &str, f64)>| {
+        edges
+            .into_iter()
+            .map(|(start, end, weight)| (String::from(start), String::from(end), weight))
+            .collect::<Vec<(String, String, f64)>>()
+    };
+    assert_eq!(
+        minimum_spanning_forest(&graph),
+        Ok(SpanningForest {
+            edges: make_edges(vec![
+                ("g", "f", -6.0),
+                ("c", "e", -4.0),
+                ("a", "b", 1.0),
+                ("b", "c", 2.0),
+                ("f", "h", 2.0),
+                ("d", "c", 3.0),
+            ]),
+            total_weight: -2.0,
+            tree_count: 3,
+        })
+    );
+    let graph = Graph::from_edges(&[("a", "b", f64::NAN)]);
+    assert!(minimum_spanning_forest(&graph).is_err());
+}
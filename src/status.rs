@@ -0,0 +1,75 @@
+use rtrpc_common::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// The state of the server answered by the built-in methods,
+/// shared by the connections, the workers and the gateway
+pub struct Status {
+    started: Instant,
+    methods: Vec<String>,
+    ready: AtomicBool,
+}
+
+impl Status {
+    /// Start the uptime, the built-in methods are served along with the methods of the list
+    pub fn new(methods: &[String]) -> Self {
+        let methods = METHODS
+            .iter()
+            .filter(|&&method| {
+                BUILTIN_METHODS.contains(&method) || methods.iter().any(|m| m == method)
+            })
+            .map(|&method| String::from(method))
+            .collect();
+        Status {
+            started: Instant::now(),
+            methods,
+            ready: AtomicBool::new(true),
+        }
+    }
+    /// Set whether the server accepts new connections
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+    pub fn health(&self) -> Health {
+        Health {
+            live: true,
+            ready: self.ready.load(Ordering::Relaxed),
+        }
+    }
+    pub fn info(&self) -> ServerInfo {
+        ServerInfo {
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            uptime: self.started.elapsed().as_secs(),
+            methods: self.methods.clone(),
+        }
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        let methods: Vec<String> = METHODS.iter().map(|&method| String::from(method)).collect();
+        Status::new(&methods)
+    }
+}
+
+#[test]
+fn status_test() {
+    let status = Status::new(&[String::from("reachable")]);
+    assert_eq!(
+        status.info().methods,
+        ["reachable", "health", "server_info"]
+    );
+    assert_eq!(status.info().max_protocol_version, PROTOCOL_VERSION);
+    assert!(status.health().ready);
+    status.set_ready(false);
+    assert_eq!(
+        status.health(),
+        Health {
+            live: true,
+            ready: false
+        }
+    );
+    assert_eq!(Status::default().info().methods.len(), METHODS.len());
+}
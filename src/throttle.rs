@@ -0,0 +1,295 @@
+use rtrpc_common::ServerError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The buckets kept, beyond which the full ones and then the least recently updated ones
+/// are forgotten
+const MAX_BUCKETS: usize = 1024;
+
+/// The quotas of the clients, every one of them is unlimited when missing
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quotas {
+    /// The connections of an IP address at the same time
+    pub max_connections_per_ip: Option<usize>,
+    /// The requests of a connection computed at the same time,
+    /// the requests are computed one at a time when missing
+    pub max_in_flight: Option<usize>,
+    /// The requests per second of a principal, or of an IP address without authentication
+    pub requests_per_second: Option<f64>,
+    /// The requests a client may send at once, `requests_per_second` by default
+    pub burst: Option<f64>,
+    /// The total work of the requests computed at the same time, the work of a request
+    /// being the edges times the nodes of its graph, or times its hop limit
+    pub max_work: Option<u64>,
+}
+
+/// The token bucket of a client
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Enforce the quotas, shared by the connections, the workers and the gateway.
+/// The refused connections and requests are answered with `ServerError::Overloaded`.
+pub struct Throttle {
+    quotas: Quotas,
+    connections: Mutex<HashMap<IpAddr, usize>>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// The work of the requests being computed
+    work: AtomicU64,
+}
+
+impl Throttle {
+    pub fn new(quotas: Quotas) -> Self {
+        Throttle {
+            quotas,
+            connections: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+            work: AtomicU64::new(0),
+        }
+    }
+    pub fn max_in_flight(&self) -> Option<usize> {
+        self.quotas.max_in_flight
+    }
+    /// Count a connection of the IP address until the returned guard is dropped
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Result<Connected, ServerError> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(ip).or_insert(0);
+        if let Some(limit) = self.quotas.max_connections_per_ip {
+            if *count >= limit {
+                return Err(ServerError::Overloaded(format!(
+                    "{} has {} connections already.",
+                    ip, count
+                )));
+            }
+        }
+        *count += 1;
+        Ok(Connected {
+            throttle: self.clone(),
+            ip,
+        })
+    }
+    /// Take a token from the bucket of the client
+    pub fn admit(&self, client: &str, now: Instant) -> Result<(), ServerError> {
+        let rate = match self.quotas.requests_per_second {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let burst = self.quotas.burst.unwrap_or(rate).max(1.0);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(client) {
+            let refill = |bucket: &Bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate
+            };
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+            // the clients rotating faster than the refill keep their buckets partly drained,
+            // a quarter of them is forgotten at once so the buckets are not sorted every time
+            if buckets.len() >= MAX_BUCKETS {
+                let mut updated: Vec<(Instant, String)> = buckets
+                    .iter()
+                    .map(|(client, bucket)| (bucket.updated, client.clone()))
+                    .collect();
+                updated.sort();
+                for (_, client) in &updated[..=buckets.len() - MAX_BUCKETS * 3 / 4] {
+                    buckets.remove(client);
+                }
+            }
+        }
+        let bucket = buckets.entry(String::from(client)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(ServerError::Overloaded(format!(
+                "{} exceeds {} requests per second.",
+                client, rate
+            )));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+    /// Reserve the work of a request until the returned guard is dropped.
+    /// A request exceeding the whole budget is only computed alone.
+    pub fn reserve(&self, work: u64) -> Result<Reserved<'_>, ServerError> {
+        if let Some(limit) = self.quotas.max_work {
+            let reserved = self
+                .work
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |busy| {
+                    if busy == 0 || busy.saturating_add(work) <= limit {
+                        Some(busy + work)
+                    } else {
+                        None
+                    }
+                });
+            if reserved.is_err() {
+                return Err(ServerError::Overloaded(format!(
+                    "The work of {} exceeds the remaining budget.",
+                    work
+                )));
+            }
+        }
+        Ok(Reserved {
+            throttle: self,
+            work,
+        })
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(Quotas::default())
+    }
+}
+
+/// A connection of an IP address, counted until it is dropped
+pub struct Connected {
+    throttle: Arc<Throttle>,
+    ip: IpAddr,
+}
+
+impl Connected {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        let mut connections = self.throttle.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The work of a request being computed, released when it is dropped
+pub struct Reserved<'a> {
+    throttle: &'a Throttle,
+    work: u64,
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        if self.throttle.quotas.max_work.is_some() {
+            self.throttle.work.fetch_sub(self.work, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+fn quotas() -> Quotas {
+    Quotas {
+        max_connections_per_ip: Some(2),
+        max_in_flight: Some(4),
+        requests_per_second: Some(2.0),
+        burst: Some(3.0),
+        max_work: Some(100),
+    }
+}
+
+#[test]
+fn connection_quota_test() {
+    let throttle = Arc::new(Throttle::new(quotas()));
+    let ip = IpAddr::from([10, 0, 0, 1]);
+    let first = throttle.connect(ip).unwrap();
+    let _second = throttle.connect(ip).unwrap();
+    assert!(throttle.connect(ip).is_err());
+    assert!(throttle.connect(IpAddr::from([10, 0, 0, 2])).is_ok());
+    assert_eq!(first.ip(), ip);
+    drop(first);
+    assert!(throttle.connect(ip).is_ok());
+}
+
+#[test]
+fn rate_quota_test() {
+    use std::time::Duration;
+
+    let throttle = Throttle::new(quotas());
+    let now = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(throttle.admit("router", now), Ok(()));
+    }
+    assert!(matches!(
+        throttle.admit("router", now),
+        Err(ServerError::Overloaded(_))
+    ));
+    assert_eq!(throttle.admit("batch", now), Ok(()));
+    let later = now + Duration::from_millis(500);
+    assert_eq!(throttle.admit("router", later), Ok(()));
+    assert!(throttle.admit("router", later).is_err());
+}
+
+#[test]
+fn work_quota_test() {
+    let throttle = Throttle::new(quotas());
+    let large = throttle.reserve(80).unwrap();
+    assert!(throttle.reserve(30).is_err());
+    let small = throttle.reserve(20).unwrap();
+    drop(large);
+    assert!(throttle.reserve(80).is_ok());
+    drop(small);
+    assert!(throttle.reserve(1000).is_ok());
+}
+
+#[test]
+fn rotating_clients_test() {
+    use std::time::Duration;
+
+    // the clients rotating faster than the refill have their least recently updated
+    // buckets forgotten
+    let throttle = Throttle::new(Quotas {
+        requests_per_second: Some(0.001),
+        ..Quotas::default()
+    });
+    let now = Instant::now();
+    for i in 0..2 * MAX_BUCKETS {
+        let rotated = now + Duration::from_millis(i as u64);
+        assert!(throttle.admit(&format!("rotated-{}", i), rotated).is_ok());
+        assert!(throttle.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+    }
+    let buckets = throttle.buckets.lock().unwrap();
+    assert!(!buckets.contains_key("rotated-0"));
+    assert!(buckets.contains_key(&format!("rotated-{}", 2 * MAX_BUCKETS - 1)));
+}
+
+#[test]
+fn hop_limit_work_test() {
+    use rtrpc_common::{Graph, Request};
+
+    // a hop limit is charged for its rounds rather than for the nodes
+    let graph = Graph::from_edges(&[("a", "b", 1.0), ("b", "a", -2.0)]);
+    let request = |max_hops| Request::ShortestPath {
+        graph: graph.clone(),
+        start: String::from("a"),
+        end: String::from("b"),
+        max_hops,
+    };
+    assert_eq!(request(None).work(), 4);
+    assert_eq!(request(Some(1000)).work(), 2000);
+    let throttle = Throttle::new(quotas());
+    assert!(throttle.reserve(request(Some(1000)).work()).is_ok());
+}
+
+#[test]
+fn unlimited_quotas_test() {
+    let unlimited = Arc::new(Throttle::default());
+    let ip = IpAddr::from([10, 0, 0, 1]);
+    let connections: Vec<_> = (0..10).map(|_| unlimited.connect(ip)).collect();
+    assert!(connections.iter().all(Result::is_ok));
+    let now = Instant::now();
+    for _ in 0..10 {
+        assert_eq!(unlimited.admit("router", now), Ok(()));
+        assert!(unlimited.reserve(u64::MAX).is_ok());
+    }
+}